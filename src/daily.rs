@@ -0,0 +1,274 @@
+use super::Grid;
+use crate::generator::{Difficulty, GenerationError, KenkenGenerator};
+use crate::solver::KenkenPuzzle;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+
+/// Calendar date (proleptic Gregorian) used to pick puzzle of the day
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u8,
+    pub day: u8,
+}
+impl Date {
+    /// Returns `None` if `month` or `day` doesn't exist in given year
+    pub fn new(year: i32, month: u8, day: u8) -> Option<Self> {
+        if month == 0 || month > 12 || day == 0 || day > days_in_month(year, month) {
+            return None;
+        }
+        Some(Self { year, month, day })
+    }
+    /// Days since 1970-01-01, negative for earlier dates
+    pub fn days_since_epoch(&self) -> i64 {
+        //Howard Hinnant's days_from_civil
+        let y = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = y.div_euclid(400);
+        let yoe = y.rem_euclid(400);
+        let m = self.month as i64;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146097 + doe - 719468
+    }
+}
+fn days_in_month(year: i32, month: u8) -> u8 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 => {
+            if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) {
+                29
+            } else {
+                28
+            }
+        }
+        _ => 31,
+    }
+}
+
+/// Why `daily_puzzle` gave no puzzle
+#[derive(Clone, Debug)]
+pub enum DailyError {
+    /// Size is outside of `DAILY_SIZES`
+    UnsupportedSize,
+    /// Difficulty isn't reliably reached at this size, only `Difficulty::Extreme` needs `DAILY_EXTREME_MIN_SIZE`
+    UnreachableDifficulty,
+    /// Generator stopped at `DAILY_MAX_ATTEMPTS` or `DAILY_TIME_LIMIT`
+    Generation(GenerationError),
+}
+
+/// Sizes supported by `daily_puzzle`.
+/// Smaller sizes don't have enough distinct grids for `DAILY_MIN_PERIOD` days, 4x4 has only 576,
+/// bigger sizes take too long to generate.
+pub const DAILY_SIZES: RangeInclusive<u8> = 5..=8;
+/// Smallest size for `Difficulty::Extreme`, smaller grids rarely need more than five nested guesses
+pub const DAILY_EXTREME_MIN_SIZE: u8 = 6;
+/// Candidate puzzles tried by `daily_puzzle` before giving up
+pub const DAILY_MAX_ATTEMPTS: u64 = 100_000;
+/// Time `daily_puzzle` spends generating before giving up
+pub const DAILY_TIME_LIMIT: Duration = Duration::from_secs(30);
+/// Shortest `daily_period` of supported sizes, 400 years of Gregorian calendar
+pub const DAILY_MIN_PERIOD: u64 = 146_097;
+
+/// Number of consecutive days for which `daily_puzzle` is guaranteed to give distinct solution grids,
+/// after that grids repeat. At least `DAILY_MIN_PERIOD` for sizes in `DAILY_SIZES`,
+/// smaller sizes run out of grids sooner. Saturates at `u64::MAX` for big sizes.
+pub fn daily_period(size: u8) -> u64 {
+    base_count(size).saturating_mul(base_period(size))
+}
+
+/// Returns puzzle of the day for `date`.
+/// Every caller gets the same puzzle for the same `date`, `size` and `difficulty`, so nothing needs to be stored.
+/// Puzzle always has unique solution.
+///
+/// Solution grid is derived from the date by picking one of fixed reduced grids (first row and column in order),
+/// relabeling its digits and reordering its rows. That is a one-to-one mapping, so any two dates
+/// less than `daily_period(size)` days apart get different solution grids and so different puzzles.
+/// The cages are then generated from a seed derived from the date.
+///
+/// Returns `DailyError` for `size` outside of `DAILY_SIZES`, `Difficulty::Extreme` below `DAILY_EXTREME_MIN_SIZE`,
+/// or if no puzzle of requested `difficulty` was found within `DAILY_MAX_ATTEMPTS` and `DAILY_TIME_LIMIT`.
+pub fn daily_puzzle(
+    date: Date,
+    size: u8,
+    difficulty: Difficulty,
+) -> Result<KenkenPuzzle, DailyError> {
+    if !DAILY_SIZES.contains(&size) {
+        return Err(DailyError::UnsupportedSize);
+    }
+    if difficulty == Difficulty::Extreme && size < DAILY_EXTREME_MIN_SIZE {
+        return Err(DailyError::UnreachableDifficulty);
+    }
+    let days = date.days_since_epoch();
+    let grid = daily_grid(days, size);
+    let mut gen = KenkenGenerator::new(size, difficulty, 40, true, 4, None);
+    gen.seed = Some(daily_seed(days, size, difficulty));
+    gen.limits.max_attempts = Some(DAILY_MAX_ATTEMPTS);
+    gen.limits.deadline = Some(Instant::now() + DAILY_TIME_LIMIT);
+    match gen.generate_puzzles(1, true, Some(&grid)) {
+        Ok(mut puzzles) => Ok(puzzles.remove(0)),
+        Err(error) => Err(DailyError::Generation(error)),
+    }
+}
+
+/// Grids derived from one reduced grid, `n!` digit relabelings times `(n-1)!` orders of rows below the first
+fn base_period(size: u8) -> u64 {
+    factorial(size as u64).saturating_mul(factorial((size as u64).saturating_sub(1)))
+}
+/// Reduced grids needed to cover `DAILY_MIN_PERIOD` days, all of them for small sizes
+fn base_count(size: u8) -> u64 {
+    //Number of reduced Latin squares, OEIS A000315
+    const REDUCED: [u64; 7] = [1, 1, 1, 1, 4, 56, 9408];
+    let needed = DAILY_MIN_PERIOD.div_ceil(base_period(size));
+    REDUCED
+        .get(size as usize)
+        .map_or(needed, |count| needed.min(*count))
+}
+/// First `count` distinct reduced grids, they don't depend on date
+fn daily_bases(size: u8, count: usize) -> Vec<Vec<u8>> {
+    let mut rng = ChaCha8Rng::seed_from_u64(0x6b656e6b656e ^ size as u64);
+    let mut bases: Vec<Vec<u8>> = Vec::with_capacity(count);
+    while bases.len() < count {
+        let base = reduce(&Grid::random(size, &mut rng));
+        if !bases.contains(&base) {
+            bases.push(base);
+        }
+    }
+    bases
+}
+/// Relabels digits so that first row is in order, then sorts rows so that first column is too.
+/// Every grid has exactly one reduced form, and relabeling digits or reordering rows below the first keeps it.
+fn reduce(grid: &Grid) -> Vec<u8> {
+    let n = grid.1 as usize;
+    let mut digits = vec![0; n + 1];
+    for (i, value) in grid.0[..n].iter().enumerate() {
+        digits[*value as usize] = i as u8 + 1;
+    }
+    let mut rows: Vec<Vec<u8>> = grid
+        .0
+        .chunks(n)
+        .map(|row| row.iter().map(|value| digits[*value as usize]).collect())
+        .collect();
+    rows.sort();
+    rows.concat()
+}
+
+fn daily_grid(days: i64, size: u8) -> Grid {
+    let period = daily_period(size);
+    let index = days.rem_euclid(period.min(i64::MAX as u64) as i64) as u64;
+    let base = (index / base_period(size)) as usize;
+    let bases = daily_bases(size, base + 1);
+    derive_grid(&bases[base], size, index % base_period(size))
+}
+/// Relabels digits and reorders rows below the first of reduced `base`, `index` is taken modulo `base_period`
+fn derive_grid(base: &[u8], size: u8, index: u64) -> Grid {
+    let n = size as usize;
+    let digit_count = factorial(size as u64);
+    //Digits permutation is recovered from first row, rows permutation from the rest
+    let digits = nth_permutation(n, index % digit_count);
+    let rows = nth_permutation(n.saturating_sub(1), index / digit_count);
+
+    let mut grid = Vec::with_capacity(n * n);
+    for row in std::iter::once(0).chain(rows.iter().map(|r| r + 1)) {
+        for value in &base[row * n..(row + 1) * n] {
+            grid.push(digits[*value as usize - 1] as u8 + 1);
+        }
+    }
+    Grid(grid, size)
+}
+fn daily_seed(days: i64, size: u8, difficulty: Difficulty) -> u64 {
+    //splitmix64 finalizer, keeps nearby dates far apart
+    let mut z = (days as u64)
+        .wrapping_mul(0x9e3779b97f4a7c15)
+        .wrapping_add((size as u64) << 8 | difficulty as u64);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+fn factorial(n: u64) -> u64 {
+    (1..=n).fold(1u64, |acc, x| acc.saturating_mul(x))
+}
+/// Permutation of `0..n` with lexicographic rank `index` (taken modulo `n!`)
+fn nth_permutation(n: usize, mut index: u64) -> Vec<usize> {
+    let mut pool: Vec<usize> = (0..n).collect();
+    let mut permutation = Vec::with_capacity(n);
+    for i in (0..n).rev() {
+        let f = factorial(i as u64);
+        let pos = ((index / f) % (i as u64 + 1)) as usize;
+        index %= f;
+        permutation.push(pool.remove(pos));
+    }
+    permutation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn date_to_days() {
+        assert_eq!(Date::new(1970, 1, 1).unwrap().days_since_epoch(), 0);
+        assert_eq!(Date::new(2000, 3, 1).unwrap().days_since_epoch(), 11017);
+        assert_eq!(Date::new(1969, 12, 31).unwrap().days_since_epoch(), -1);
+        assert!(Date::new(2023, 2, 29).is_none());
+        assert!(Date::new(2024, 2, 29).is_some());
+    }
+    #[test]
+    fn daily_grids_are_distinct_within_period() {
+        let mut seen = Vec::new();
+        for days in 0..daily_period(4) as i64 {
+            let grid = daily_grid(days, 4);
+            assert!(!seen.contains(&grid.0));
+            seen.push(grid.0);
+        }
+        assert_eq!(seen.len(), 576); //every 4x4 grid, from all four reduced ones
+        assert_eq!(daily_grid(3, 4).0, daily_grid(3 + 576, 4).0); //grids repeat after the period
+    }
+    #[test]
+    fn daily_period_spans_four_centuries() {
+        for size in DAILY_SIZES {
+            assert!(daily_period(size) >= DAILY_MIN_PERIOD);
+        }
+        //Distinct reduced bases and one-to-one mapping within each of them
+        let bases = daily_bases(5, base_count(5) as usize);
+        assert_eq!(bases.len(), 51);
+        for (i, base) in bases.iter().enumerate() {
+            assert_eq!(&reduce(&Grid(base.clone(), 5)), base);
+            assert_eq!(&reduce(&daily_grid(i as i64 * 2880 + 1234, 5)), base);
+        }
+        let grids: Vec<Vec<u8>> = (0..2880).map(|i| derive_grid(&bases[7], 5, i).0).collect();
+        assert!((1..grids.len()).all(|i| !grids[..i].contains(&grids[i])));
+    }
+    #[test]
+    fn daily_rejects_unreachable_settings() {
+        let date = Date::new(2024, 6, 1).unwrap();
+        assert!(matches!(
+            daily_puzzle(date, 4, Difficulty::Easy),
+            Err(DailyError::UnsupportedSize)
+        ));
+        assert!(matches!(
+            daily_puzzle(date, 9, Difficulty::Easy),
+            Err(DailyError::UnsupportedSize)
+        ));
+        assert!(matches!(
+            daily_puzzle(date, 5, Difficulty::Extreme),
+            Err(DailyError::UnreachableDifficulty)
+        ));
+    }
+    #[test]
+    fn daily_puzzle_is_stable() {
+        let date = Date::new(2024, 6, 1).unwrap();
+        let next = Date::new(2024, 6, 2).unwrap();
+        let puzzle = daily_puzzle(date, 5, Difficulty::Easy).unwrap();
+        assert_eq!(
+            puzzle.format(),
+            daily_puzzle(date, 5, Difficulty::Easy).unwrap().format()
+        );
+        let solution = puzzle.solve(&40, &2).unwrap().unwrap();
+        assert_eq!(solution.len(), 1);
+        assert_eq!(solution[0].grid.0, daily_grid(date.days_since_epoch(), 5).0);
+        let other = daily_puzzle(next, 5, Difficulty::Easy).unwrap();
+        assert_ne!(puzzle.format(), other.format());
+    }
+}
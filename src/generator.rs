@@ -1,41 +1,287 @@
-use super::{Cage, Grid, MathOp};
-use crate::solver::KenkenPuzzle;
-use rand::{distributions::WeightedIndex, prelude::Distribution, seq::SliceRandom, Rng};
-use std::cmp::{max, min};
+use super::{Cage, Grid, Inequality, MathOp, Rules};
+pub use crate::solver::CancellationToken;
+use crate::solver::{Ambiguity, KenkenPuzzle, PuzzleError, Solution, SolverError, SolverOptions};
+use rand::{
+    distributions::WeightedIndex, prelude::Distribution, seq::SliceRandom, Rng, SeedableRng,
+};
+use rand_chacha::ChaCha8Rng;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::collections::VecDeque;
+use std::time::Instant;
 
-///`Difficulty` defines target difficulty for puzzle from generator
+///`Difficulty` defines target difficulty for puzzle from generator.
+/// It is rated by `Solution::depth`, the number of nested guesses on the way to the solution.
+/// Candidates rejected before the right one don't count, so a puzzle needing many wrong tries
+/// at one cell still rates by how deep the guesses go.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Difficulty {
+    /// Solved by deduction alone, without guessing
     Easy,
+    /// One or two nested guesses
     Medium,
+    /// Three to five nested guesses
     Hard,
+    /// More than five nested guesses
     Extreme,
     Any, //nolimit
 }
 impl Difficulty {
-    //Difficulty settings based on depth
-    fn from_depth(depth: usize) -> Self {
+    /// Difficulty settings based on `Solution::depth`, see `Difficulty`
+    pub fn from_depth(depth: usize) -> Self {
         if depth == 0 {
             return Difficulty::Easy;
         }
-        if depth >= 1 && depth <= 2 {
+        if (1..=2).contains(&depth) {
             return Difficulty::Medium;
         }
-        if depth >= 3 && depth <= 5 {
+        if (3..=5).contains(&depth) {
             return Difficulty::Hard;
         }
 
-        return Difficulty::Extreme;
+        Difficulty::Extreme
     }
     fn test_depth(&self, depth: usize) -> bool {
         match self {
-            Difficulty::Any => {
-                return true;
+            Difficulty::Any => true,
+            d => *d == Difficulty::from_depth(depth),
+        }
+    }
+}
+/// Bounds for `KenkenGenerator::generate_puzzles`, everything is unlimited by default.
+/// Limits are checked between candidate puzzles, `deadline` and `cancel` also stop the solver inside a candidate.
+#[derive(Clone, Debug, Default)]
+pub struct GenerationLimits {
+    /// Maximum number of candidate puzzles tried, valid or not
+    pub max_attempts: Option<u64>,
+    /// Generation stops once this instant has passed
+    pub deadline: Option<Instant>,
+    /// Generation stops once this token is cancelled
+    pub cancel: Option<CancellationToken>,
+}
+/// Reason why generation stopped before finding enough puzzles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// Tried `max_attempts` candidates
+    AttemptsExhausted,
+    /// `deadline` has passed
+    DeadlineExceeded,
+    /// `cancel` token was cancelled
+    Cancelled,
+    /// `rules` can't be used for `size`, so no candidate was tried, see `KenkenPuzzle::validate`
+    InvalidRules(PuzzleError),
+}
+/// Defines generator errors
+#[derive(Clone, Debug)]
+pub struct GenerationError {
+    pub reason: StopReason,
+    /// Number of candidates tried before stopping
+    pub attempts: u64,
+    /// Puzzles accepted before stopping
+    pub puzzles: Vec<KenkenPuzzle>,
+}
+impl GenerationLimits {
+    fn check(&self, attempts: u64) -> Option<StopReason> {
+        if let Some(reason) = self.interrupted() {
+            return Some(reason);
+        }
+        if let Some(max_attempts) = self.max_attempts {
+            if attempts >= max_attempts {
+                return Some(StopReason::AttemptsExhausted);
+            }
+        }
+        None
+    }
+    /// Checks limits that don't depend on attempts count
+    fn interrupted(&self) -> Option<StopReason> {
+        if let Some(token) = &self.cancel {
+            if token.is_cancelled() {
+                return Some(StopReason::Cancelled);
             }
-            d => {
-                return *d == Difficulty::from_depth(depth);
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Some(StopReason::DeadlineExceeded);
             }
         }
+        None
+    }
+}
+/// Puzzle accepted by generator
+#[derive(Clone, Debug)]
+pub struct GeneratedPuzzle {
+    pub puzzle: KenkenPuzzle,
+    /// Grid the cages were created from
+    pub grid: Grid,
+    /// Solution found during validation, `None` if generated without validation
+    pub solution: Option<Solution>,
+    /// Difficulty measured during validation, `None` if generated without validation
+    pub difficulty: Option<Difficulty>,
+    /// Number of changes needed to make the puzzle unique
+    pub repairs: u32,
+}
+/// Failed validation, ambiguous puzzle keeps its witness so it can be repaired
+enum Invalid {
+    Rejected(Rejection),
+    Ambiguous(Box<Ambiguity>),
+}
+/// Why candidate puzzle was thrown away
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    NoSolution,
+    /// Has more solutions while generator requires unique one, even after `max_repairs`
+    NotUnique,
+    /// Contains measured difficulty
+    WrongDifficulty(Difficulty),
+    /// Solver exceeded `max_depth`, ran out of time before `deadline` or was cancelled
+    SolverLimit(SolverError),
+}
+/// Counters describing generation so far
+#[derive(Clone, Debug, Default)]
+pub struct GenerationProgress {
+    /// Candidate puzzles tried
+    pub attempts: u64,
+    pub accepted: u64,
+    /// Accepted puzzles which were ambiguous at first
+    pub repaired: u64,
+    pub no_solution: u64,
+    pub not_unique: u64,
+    pub wrong_difficulty: u64,
+    pub solver_limit: u64,
+    /// Rejection of the last candidate, `None` if it was accepted
+    pub last_rejection: Option<Rejection>,
+}
+impl GenerationProgress {
+    fn record(&mut self, rejection: Option<Rejection>) {
+        self.attempts += 1;
+        match rejection {
+            None => self.accepted += 1,
+            Some(Rejection::NoSolution) => self.no_solution += 1,
+            Some(Rejection::NotUnique) => self.not_unique += 1,
+            Some(Rejection::WrongDifficulty(_)) => self.wrong_difficulty += 1,
+            Some(Rejection::SolverLimit(_)) => self.solver_limit += 1,
+        }
+        self.last_rejection = rejection;
+    }
+}
+type ProgressCallback<'a> = Box<dyn FnMut(&GenerationProgress) + 'a>;
+type Candidate = Result<GeneratedPuzzle, Rejection>;
+/// Iterator over accepted puzzles, created by `KenkenGenerator::puzzles`
+pub struct PuzzleStream<'a, R: Rng> {
+    generator: &'a KenkenGenerator,
+    validate: bool,
+    grid: Option<&'a Grid>,
+    rng: R,
+    /// When set, candidate `n` draws from its own rng derived from seed and `n` instead of `rng`
+    seed: Option<u64>,
+    /// Candidates computed ahead, `None` when skipped because generation was interrupted
+    pending: VecDeque<Option<Candidate>>,
+    progress: GenerationProgress,
+    callback: Option<ProgressCallback<'a>>,
+    stop_reason: Option<StopReason>,
+}
+impl<'a, R: Rng> PuzzleStream<'a, R> {
+    /// `callback` is called after every tried candidate.
+    /// To report progress to another thread, send it through a channel from `callback`.
+    pub fn on_progress(mut self, callback: impl FnMut(&GenerationProgress) + 'a) -> Self {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+    pub fn progress(&self) -> &GenerationProgress {
+        &self.progress
+    }
+    /// Reason why iteration ended, `None` while it is running
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+    fn next_candidate(&mut self) -> Option<Candidate> {
+        let gen = self.generator;
+        let (validate, grid) = (self.validate, self.grid);
+        let seed = match self.seed {
+            Some(seed) => seed,
+            None => return gen.try_candidate(validate, grid, &mut self.rng),
+        };
+        if self.pending.is_empty() {
+            //Candidates are independent, so batch can be computed in any order and still give the same results
+            let first = self.progress.attempts;
+            let mut batch = batch_size();
+            if let Some(max_attempts) = gen.limits.max_attempts {
+                batch = batch.min(max_attempts - first);
+            }
+            let run = |index: u64| {
+                if gen.limits.interrupted().is_some() {
+                    return None;
+                }
+                gen.try_candidate(validate, grid, &mut candidate_rng(seed, index))
+            };
+            #[cfg(feature = "parallel")]
+            let results: Vec<_> = (first..first + batch).into_par_iter().map(run).collect();
+            #[cfg(not(feature = "parallel"))]
+            let results: Vec<_> = (first..first + batch).map(run).collect();
+            self.pending.extend(results);
+        }
+        self.pending.pop_front().unwrap()
+    }
+}
+#[cfg(feature = "parallel")]
+fn batch_size() -> u64 {
+    rayon::current_num_threads() as u64
+}
+#[cfg(not(feature = "parallel"))]
+fn batch_size() -> u64 {
+    1
+}
+fn candidate_rng(seed: u64, index: u64) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(index);
+    rng
+}
+impl<R: Rng> Iterator for PuzzleStream<'_, R> {
+    type Item = GeneratedPuzzle;
+    fn next(&mut self) -> Option<GeneratedPuzzle> {
+        let gen = self.generator;
+        loop {
+            if self.stop_reason.is_some() {
+                return None;
+            }
+            if let Some(reason) = gen.limits.check(self.progress.attempts) {
+                self.stop_reason = Some(reason);
+                return None;
+            }
+            let result = match self.next_candidate() {
+                Some(result) => result,
+                None => continue,
+            };
+            self.progress.record(result.as_ref().err().copied());
+            if result.as_ref().is_ok_and(|generated| generated.repairs > 0) {
+                self.progress.repaired += 1;
+            }
+            if let Some(callback) = &mut self.callback {
+                callback(&self.progress);
+            }
+            if result.is_ok() {
+                return result.ok();
+            }
+        }
+    }
+}
+fn collect_puzzles<R: Rng>(stream: &mut PuzzleStream<R>, count: u32) -> Vec<KenkenPuzzle> {
+    stream
+        .take(count as usize)
+        .map(|generated| generated.puzzle)
+        .collect()
+}
+fn finish_collect<R: Rng>(
+    stream: &PuzzleStream<R>,
+    puzzles: Vec<KenkenPuzzle>,
+) -> Result<Vec<KenkenPuzzle>, GenerationError> {
+    match stream.stop_reason() {
+        Some(reason) => Err(GenerationError {
+            reason,
+            attempts: stream.progress().attempts,
+            puzzles,
+        }),
+        None => Ok(puzzles),
     }
 }
 enum Directions {
@@ -52,7 +298,25 @@ pub struct KenkenGenerator {
     pub max_depth: usize,
     pub unique: bool,
     pub max_cage_size: usize,
-    pub operation_weight: [f64; 5], //for each option in MathOp,
+    pub operation_weight: [f64; 12], //for each option in MathOp::KNOWN,
+    /// If set, every call of `generate_puzzles` produces the same puzzles for the same seed and parameters
+    pub seed: Option<u64>,
+    /// Stops `generate_puzzles` when the requested puzzles can't be found in time
+    pub limits: GenerationLimits,
+    /// How many times cages of ambiguous puzzle are changed before it's thrown away, only with `unique`
+    pub max_repairs: u32,
+    /// Number of starter digits from the solution revealed in each puzzle, see `KenkenPuzzle::givens`
+    pub givens: usize,
+    /// Rule variant of generated puzzles, generation stops with `StopReason::InvalidRules` if it isn't valid for `size`
+    pub rules: Rules,
+    /// Cages with more than one cell and classic operation show only the target, see `MathOp::Unknown`
+    pub hide_operations: bool,
+    /// Ambiguous puzzles can also be repaired by adding inequality between neighboring cells,
+    /// see `KenkenPuzzle::inequalities`
+    pub inequalities: bool,
+    /// Every generated grid gets random jigsaw regions, see `KenkenPuzzle::regions`.
+    /// Grid passed to `generate_puzzles` keeps only rows and columns.
+    pub regions: bool,
 }
 
 impl KenkenGenerator {
@@ -64,16 +328,24 @@ impl KenkenGenerator {
         max_cage_size: usize,
         operations: Option<[f64; 5]>,
     ) -> Self {
+        //Operations after the classic five are off unless weighted in `operation_weight`
+        let mut operation_weight = [0.0; 12];
+        operation_weight[..5].copy_from_slice(&operations.unwrap_or([1.0, 1.3, 1.0, 1.6, 0.15]));
         Self {
             size,
             difficulty,
             max_depth,
             unique,
             max_cage_size,
-            operation_weight: match operations {
-                Some(x) => x,
-                None => [1.0, 1.3, 1.0, 1.6, 0.15],
-            },
+            operation_weight,
+            seed: None,
+            limits: GenerationLimits::default(),
+            max_repairs: 10,
+            givens: 0,
+            rules: Rules::default(),
+            hide_operations: false,
+            inequalities: false,
+            regions: false,
         }
     }
     ///Generate KenKen puzzles with current generator instance.
@@ -81,74 +353,210 @@ impl KenkenGenerator {
     /// * `count` - Target number of puzzles to return.
     /// * `validate` - Throw away every puzzle that isn't valid KenKen puzzle and doesn't satisfy `difficulty` and 'unique'.
     /// * `grid` - If specified, this grid will be used to create cages, instead of random grid.
+    ///
+    /// Randomness comes from `seed` when it is set, otherwise from `rand::thread_rng()`.
+    /// If any of `limits` is reached first, returns `GenerationError` with puzzles found so far.
+    ///
+    /// With `parallel` feature candidates are tried on all cores.
+    /// Each candidate has its own random stream, so for the same `seed` the result doesn't depend on number of threads.
     pub fn generate_puzzles(
         &self,
         count: u32,
         validate: bool,
         grid: Option<&Grid>,
-    ) -> Vec<KenkenPuzzle> {
-        let mut counter = 0;
-        let mut total = 0;
-        let mut puzzles = Vec::<KenkenPuzzle>::new();
-        while counter != count {
-            let puzzle;
-            if let Some(grid) = grid {
-                puzzle = self.generate_puzzle_with_grid(grid);
-            } else {
-                puzzle = self.generate_puzzle();
-            };
-            total += 1;
-            if !validate || self.validate_puzzle(&puzzle) {
-                puzzles.push(puzzle);
-                counter += 1;
-            }
+    ) -> Result<Vec<KenkenPuzzle>, GenerationError> {
+        let mut stream = self.puzzles(validate, grid);
+        let puzzles = collect_puzzles(&mut stream, count);
+        finish_collect(&stream, puzzles)
+    }
+    ///Same as `generate_puzzles`, but every random choice is drawn from `rng`.
+    /// The same `rng` state always yields the same puzzles.
+    pub fn generate_puzzles_with_rng<R: Rng + ?Sized>(
+        &self,
+        count: u32,
+        validate: bool,
+        grid: Option<&Grid>,
+        rng: &mut R,
+    ) -> Result<Vec<KenkenPuzzle>, GenerationError> {
+        let mut stream = self.puzzles_with_rng(validate, grid, rng);
+        let puzzles = collect_puzzles(&mut stream, count);
+        finish_collect(&stream, puzzles)
+    }
+    /// Returns endless iterator of accepted puzzles, arguments are the same as in `generate_puzzles`.
+    /// Iteration ends when any of `limits` is reached, see `PuzzleStream::stop_reason`.
+    /// Uses all cores with `parallel` feature, like `generate_puzzles`.
+    pub fn puzzles<'a>(
+        &'a self,
+        validate: bool,
+        grid: Option<&'a Grid>,
+    ) -> PuzzleStream<'a, ChaCha8Rng> {
+        let seed = self.seed.unwrap_or_else(|| rand::thread_rng().gen());
+        let mut stream = self.puzzles_with_rng(validate, grid, ChaCha8Rng::seed_from_u64(seed));
+        stream.seed = Some(seed);
+        stream
+    }
+    /// Same as `puzzles`, but every random choice is drawn from `rng`, one candidate after another
+    pub fn puzzles_with_rng<'a, R: Rng>(
+        &'a self,
+        validate: bool,
+        grid: Option<&'a Grid>,
+        rng: R,
+    ) -> PuzzleStream<'a, R> {
+        let mut layout = KenkenPuzzle::new(self.size, vec![]);
+        layout.rules = self.rules;
+        PuzzleStream {
+            generator: self,
+            validate,
+            grid,
+            rng,
+            seed: None,
+            pending: VecDeque::new(),
+            progress: GenerationProgress::default(),
+            callback: None,
+            stop_reason: layout.validate().err().map(StopReason::InvalidRules),
         }
-        println!("Generated: {total} / Valid {counter}");
-        return puzzles;
     }
-    fn validate_puzzle(&self, puzzle: &KenkenPuzzle) -> bool {
-        if let Ok(solutions) = puzzle.solve(&self.max_depth, &2) {
-            if let Some(solutions) = solutions {
-                println!("sol count: {}", solutions.len());
-                for sol in &solutions {
-                    println!("depth: {}", sol.depth);
+    /// Creates and checks one candidate, `None` if generation was interrupted meanwhile
+    fn try_candidate<R: Rng + ?Sized>(
+        &self,
+        validate: bool,
+        grid: Option<&Grid>,
+        rng: &mut R,
+    ) -> Option<Candidate> {
+        let (mut puzzle, grid) = match grid {
+            Some(grid) => (
+                self.generate_puzzle_with_grid(grid, rng).ok()?,
+                grid.clone(),
+            ),
+            None => self.generate_puzzle(rng).ok()?,
+        };
+        let mut repairs = 0;
+        let (solution, difficulty) = if validate {
+            let (solution, difficulty) = loop {
+                match self.validate_puzzle(&puzzle) {
+                    Ok(result) => break result,
+                    Err(Invalid::Ambiguous(ambiguity)) if repairs < self.max_repairs => {
+                        self.repair_puzzle(&mut puzzle, &grid, &ambiguity, rng);
+                        repairs += 1;
+                    }
+                    Err(Invalid::Ambiguous(_)) => return Some(Err(Rejection::NotUnique)),
+                    Err(Invalid::Rejected(rejection)) => return Some(Err(rejection)),
                 }
+            };
+            (Some(solution), Some(difficulty))
+        } else {
+            (None, None)
+        };
+        Some(Ok(GeneratedPuzzle {
+            puzzle,
+            grid,
+            solution,
+            difficulty,
+            repairs,
+        }))
+    }
+    fn validate_puzzle(&self, puzzle: &KenkenPuzzle) -> Result<(Solution, Difficulty), Invalid> {
+        let options = SolverOptions {
+            max_depth: Some(self.max_depth),
+            max_solutions: Some(2),
+            time_limit: self
+                .limits
+                .deadline
+                .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            cancel: self.limits.cancel.clone(),
+            ..Default::default()
+        };
+        match puzzle.solve_with_options(&options) {
+            Ok(Some(mut solutions)) => {
                 if solutions.len() != 1 && self.unique {
-                    return false;
+                    //Witness with cells and cages where solutions differ, for repairs
+                    let second = solutions.pop().unwrap();
+                    let first = solutions.pop().unwrap();
+                    let ambiguity = puzzle.compare_solutions(first, second);
+                    return Err(Invalid::Ambiguous(Box::new(ambiguity)));
                 }
-
-                return self.difficulty.test_depth(solutions[0].depth);
+                let difficulty = Difficulty::from_depth(solutions[0].depth);
+                if !self.difficulty.test_depth(solutions[0].depth) {
+                    return Err(Invalid::Rejected(Rejection::WrongDifficulty(difficulty)));
+                }
+                Ok((solutions.swap_remove(0), difficulty))
             }
-        } else {
-            println!("too deep")
+            Ok(None) => Err(Invalid::Rejected(Rejection::NoSolution)),
+            Err(error) => Err(Invalid::Rejected(Rejection::SolverLimit(error))),
         }
-        println!("no sol");
-        return false;
     }
-    fn generate_puzzle(&self) -> KenkenPuzzle {
-        let mut grid = self.create_grid();
-        grid.shuffle(self.size as u32 * 2);
-        grid.print();
-        return self.generate_puzzle_with_grid(&grid);
+    fn generate_puzzle<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+    ) -> Result<(KenkenPuzzle, Grid), StopReason> {
+        let (grid, regions) = self.create_grid(rng)?;
+        let mut puzzle = self.generate_puzzle_with_grid(&grid, rng)?;
+        puzzle.regions = regions;
+        Ok((puzzle, grid))
     }
-    fn generate_puzzle_with_grid(&self, grid: &Grid) -> KenkenPuzzle {
+    fn generate_puzzle_with_grid<R: Rng + ?Sized>(
+        &self,
+        grid: &Grid,
+        rng: &mut R,
+    ) -> Result<KenkenPuzzle, StopReason> {
         let mut unallocated_cells: Vec<usize> = (0..(self.size as usize).pow(2)).collect();
         let mut cages = Vec::<Cage>::new();
-        while unallocated_cells.len() > 0 {
-            cages.push(self.generate_cage(&grid, &mut unallocated_cells));
+        while !unallocated_cells.is_empty() {
+            cages.push(self.generate_cage(grid, &mut unallocated_cells, rng)?);
         }
 
-        return KenkenPuzzle::new(self.size, cages);
+        let mut puzzle = KenkenPuzzle::new(self.size, cages);
+        puzzle.rules = self.rules;
+        if self.givens > 0 {
+            let cells: Vec<usize> = (0..grid.0.len()).collect();
+            for cell in cells.choose_multiple(rng, self.givens) {
+                puzzle.givens.insert(*cell, grid.0[*cell]);
+            }
+        }
+        Ok(puzzle)
     }
-    fn create_grid(&self) -> Grid {
-        Grid::new(self.size)
+    /// Random solution grid together with regions it fills, if `regions` is set
+    fn create_grid<R: Rng + ?Sized>(
+        &self,
+        rng: &mut R,
+    ) -> Result<(Grid, Vec<Vec<usize>>), StopReason> {
+        let (mut grid, regions) = if self.regions {
+            loop {
+                if let Some(reason) = self.limits.interrupted() {
+                    return Err(reason);
+                }
+                let mut layout = KenkenPuzzle::new(self.size, vec![]);
+                layout.rules = self.rules;
+                layout.regions = random_regions(self.size as usize, rng);
+                //Some layouts have no matching grid or it's hard to find, then another layout is tried
+                let max_nodes = Some(100 * (self.size as u64).pow(2));
+                if let Some(grid) =
+                    Grid::random_with(self.size, rng, |a, b| layout.peers(a, b), max_nodes)
+                {
+                    break (grid, layout.regions);
+                }
+            }
+        } else if self.rules.boxes || self.rules.diagonals {
+            let mut layout = KenkenPuzzle::new(self.size, vec![]);
+            layout.rules = self.rules;
+            let grid = Grid::random_with(self.size, rng, |a, b| layout.peers(a, b), None)
+                .expect("grid exists for valid rules");
+            (grid, vec![])
+        } else {
+            (Grid::random(self.size, rng), vec![])
+        };
+        grid.shift_digits(self.rules.min_digit);
+        Ok((grid, regions))
     }
-
-    fn generate_cage(&self, grid: &Grid, unallocated: &mut Vec<usize>) -> Cage {
-        let mut cells = Vec::<usize>::new();
-        let mut rng = rand::thread_rng();
-        let mut last = unallocated.remove(0);
-        cells.push(last);
+    /// Grows cage from the first unallocated cell in random directions.
+    /// Without `Free` operation a cell with no unallocated neighbor is skipped and the next one starts the cage,
+    /// if no cell can start it the last one gets single cell cage anyway.
+    fn generate_cage<R: Rng + ?Sized>(
+        &self,
+        grid: &Grid,
+        unallocated: &mut Vec<usize>,
+        rng: &mut R,
+    ) -> Result<Cage, StopReason> {
         let directions = [
             Directions::Up,
             Directions::Down,
@@ -157,131 +565,639 @@ impl KenkenGenerator {
         ]; //up,down,left,right
         let size = self.size as usize;
         let chance = 1.0 - (self.operation_weight[4] / self.operation_weight.iter().sum::<f64>());
-        'cage_grow: while cells.len() < self.max_cage_size
-            && rng.gen_bool(chance / cells.len() as f64)
-        {
-            for dir in directions.choose_multiple(&mut rng, directions.len()) {
-                let neighbor = match dir {
-                    Directions::Up => {
-                        if last < size {
-                            continue;
+        let mut start = 0;
+        loop {
+            if let Some(reason) = self.limits.interrupted() {
+                return Err(reason);
+            }
+            let mut last = unallocated.remove(start);
+            let mut cells = vec![last];
+            let mut retry = false;
+            'cage_grow: while cells.len() < self.max_cage_size
+                && rng.gen_bool(chance / cells.len() as f64)
+            {
+                for dir in directions.choose_multiple(rng, directions.len()) {
+                    let neighbor = match dir {
+                        Directions::Up => {
+                            if last < size {
+                                continue;
+                            }
+                            last - size
                         }
-                        last - size
-                    }
-                    Directions::Down => {
-                        if last + size >= size.pow(2) {
-                            continue;
+                        Directions::Down => {
+                            if last + size >= size.pow(2) {
+                                continue;
+                            }
+                            last + size
                         }
-                        last + size
-                    }
-                    Directions::Left => {
-                        if last % size == 0 {
-                            continue;
+                        Directions::Left => {
+                            if last.is_multiple_of(size) {
+                                continue;
+                            }
+                            last - 1
                         }
-                        last - 1
-                    }
-                    Directions::Right => {
-                        if (last + 1) % size == 0 {
-                            continue;
+                        Directions::Right => {
+                            if (last + 1).is_multiple_of(size) {
+                                continue;
+                            }
+                            last + 1
                         }
-                        last + 1
+                    };
+                    if self.rules.distinct_cages
+                        && cells.iter().any(|c| grid.0[*c] == grid.0[neighbor])
+                    {
+                        continue;
+                    }
+                    if let Ok(idx) = unallocated.binary_search(&neighbor) {
+                        last = unallocated.remove(idx);
+                        cells.push(last);
+                        continue 'cage_grow; //found new cell so continue cycle
                     }
-                };
-                if let Ok(idx) = unallocated.binary_search(&neighbor) {
-                    last = unallocated.remove(idx);
-                    cells.push(last);
-                    continue 'cage_grow; //found new cell so continue cycle
                 }
+                retry = chance == 1.0 && cells.len() == 1 && start < unallocated.len();
+                break; //no available cell found, break cycle
             }
-            if chance == 1.0 && cells.len() == 1 {
-                unallocated.push(last);
-                return self.generate_cage(grid, unallocated); //retry
+            if !retry {
+                return Ok(self.cage_from_cells(grid, cells, rng));
             }
-            break; //no available cell found, break cycle
+            unallocated.insert(start, last);
+            start += 1;
         }
-
-        let mut operation = MathOp::Free;
+    }
+    /// Picks operation for `cells` and computes target from `grid`
+    fn cage_from_cells<R: Rng + ?Sized>(
+        &self,
+        grid: &Grid,
+        mut cells: Vec<usize>,
+        rng: &mut R,
+    ) -> Cage {
         let clen = cells.len();
-        let operations = [
-            MathOp::Add,
-            MathOp::Sub,
-            MathOp::Mul,
-            MathOp::Div,
-            MathOp::Free,
-        ];
-        let mut weights = WeightedIndex::new(&self.operation_weight).unwrap();
-        loop {
-            let op = operations[weights.sample(&mut rng)];
-            match op {
-                MathOp::Add => {
-                    if clen > 1 {
-                        operation = MathOp::Add;
-                        break;
-                    }
-                    weights.update_weights(&[(0, &0f64)]).unwrap();
-                }
-                MathOp::Sub => {
-                    if clen == 2 {
-                        operation = MathOp::Sub;
-                        break;
-                    }
-                    weights.update_weights(&[(1, &0f64)]).unwrap()
+        let size = self.size as usize;
+        //Concatenation is read in cell order, so it is only used where the order is obvious
+        let straight = cells.iter().all(|i| i / size == cells[0] / size)
+            || cells.iter().all(|i| i % size == cells[0] % size);
+        let mut weights = WeightedIndex::new(self.operation_weight).unwrap();
+        let operation = loop {
+            let index = weights.sample(rng);
+            let op = MathOp::KNOWN[index];
+            let fits = match op {
+                MathOp::Add
+                | MathOp::Mul
+                | MathOp::Min
+                | MathOp::Max
+                | MathOp::Gcd
+                | MathOp::Lcm => clen > 1,
+                MathOp::Concat => clen > 1 && straight,
+                _ => op.fits(clen, &self.rules),
+            };
+            if fits {
+                if op == MathOp::Concat {
+                    cells.sort();
                 }
-                MathOp::Mul => {
-                    if clen > 1 {
-                        operation = MathOp::Mul;
-                        break;
-                    }
-                    weights.update_weights(&[(2, &0f64)]).unwrap()
+                let values: Vec<u8> = cells.iter().map(|i| grid.0[*i]).collect();
+                //Digits may be not divisible, larger than the largest one or give zero difference
+                match op.evaluate(&values) {
+                    Some(0) if op == MathOp::Sub => {}
+                    Some(_) => break op,
+                    None => {}
                 }
-                MathOp::Div => {
-                    if clen == 2 {
-                        let (n, m) = (grid.0[cells[0]], grid.0[cells[1]]);
-                        if max(n, m) % min(n, m) != 0 {
-                            continue;
-                        }
-                        operation = MathOp::Div;
-                        break;
+            }
+            //Rejected operation isn't drawn again, when no weighted one is left cage gets addition
+            if weights.update_weights(&[(index, &0f64)]).is_err() {
+                break if clen > 1 { MathOp::Add } else { MathOp::Free };
+            }
+        };
+        let values: Vec<u8> = cells.iter().map(|i| grid.0[*i]).collect();
+        let target = operation.evaluate(&values).unwrap();
+        let operation = if self.hide_operations && clen > 1 && MathOp::CLASSIC.contains(&operation)
+        {
+            MathOp::Unknown
+        } else {
+            operation
+        };
+        Cage {
+            target,
+            operation,
+            cells,
+        }
+    }
+    /// Changes one of cages where solutions in `ambiguity` differ, `grid` stays solution of `puzzle`.
+    /// The cage is split in two, joined with neighboring cage or gets new operation.
+    /// With `inequalities` an inequality ruling out one of the solutions may be added instead,
+    /// and the two cages it crosses may be joined, so the inequality takes place of a cage.
+    fn repair_puzzle<R: Rng + ?Sized>(
+        &self,
+        puzzle: &mut KenkenPuzzle,
+        grid: &Grid,
+        ambiguity: &Ambiguity,
+        rng: &mut R,
+    ) {
+        if self.inequalities && rng.gen_bool(0.5) {
+            let other = if ambiguity.first.grid.0 == grid.0 {
+                &ambiguity.second.grid.0
+            } else {
+                &ambiguity.first.grid.0
+            };
+            let size = self.size as usize;
+            //Neighbors ordered differently in the other solution
+            let pairs: Vec<(usize, usize)> = ambiguity
+                .cells
+                .iter()
+                .flat_map(|a| neighbors(*a, size).map(move |b| (*a, b)))
+                .filter(|(a, b)| (grid.0[*a] > grid.0[*b]) != (other[*a] > other[*b]))
+                .collect();
+            if let Some((a, b)) = pairs.choose(rng) {
+                let (greater, less) = if grid.0[*a] > grid.0[*b] {
+                    (*a, *b)
+                } else {
+                    (*b, *a)
+                };
+                puzzle.inequalities.push(Inequality { greater, less });
+                let cage_of = |cell| puzzle.cages.iter().position(|c| c.cells.contains(&cell));
+                if let (Some(first), Some(second)) = (cage_of(greater), cage_of(less)) {
+                    if first != second && rng.gen_bool(0.5) {
+                        self.join_cages(puzzle, grid, first, second, rng);
                     }
-                    weights.update_weights(&[(3, &0f64)]).unwrap()
                 }
-                MathOp::Free => {
-                    if clen == 1 {
-                        break;
+                return;
+            }
+        }
+        let index = *ambiguity.cages.choose(rng).unwrap();
+        let cells = puzzle.cages[index].cells.clone();
+        //Single cell cages need Free operation
+        let min_part = if self.operation_weight[4] > 0.0 { 1 } else { 2 };
+        match rng.gen_range(0..3) {
+            0 if cells.len() >= 2 * min_part => {
+                //Cells are ordered start-end, so both parts stay connected
+                let at = rng.gen_range(min_part..=cells.len() - min_part);
+                let (first, second) = cells.split_at(at);
+                puzzle.cages[index] = self.cage_from_cells(grid, first.to_vec(), rng);
+                let cage = self.cage_from_cells(grid, second.to_vec(), rng);
+                puzzle.cages.push(cage);
+                return;
+            }
+            1 => {
+                let mut others: Vec<usize> =
+                    (0..puzzle.cages.len()).filter(|i| *i != index).collect();
+                others.shuffle(rng);
+                for other in others {
+                    if self.join_cages(puzzle, grid, index, other, rng) {
+                        return;
                     }
-                    weights.update_weights(&[(4, &0f64)]).unwrap()
                 }
             }
+            _ => (),
         }
-        let target = match operation {
-            MathOp::Add => {
-                let mut sum = 0;
-                for idx in &cells {
-                    sum += grid.0[*idx] as u32;
-                }
-                sum
+        puzzle.cages[index] = self.cage_from_cells(grid, cells, rng);
+    }
+    /// Joins cage `other` into cage `index` with new operation, unless the result is too big,
+    /// repeats a digit under `distinct_cages` or isn't a path. Returns whether cages were joined.
+    fn join_cages<R: Rng + ?Sized>(
+        &self,
+        puzzle: &mut KenkenPuzzle,
+        grid: &Grid,
+        index: usize,
+        other: usize,
+        rng: &mut R,
+    ) -> bool {
+        let (cells, other_cells) = (&puzzle.cages[index].cells, &puzzle.cages[other].cells);
+        if cells.len() + other_cells.len() > self.max_cage_size {
+            return false;
+        }
+        if self.rules.distinct_cages
+            && cells
+                .iter()
+                .any(|a| other_cells.iter().any(|b| grid.0[*a] == grid.0[*b]))
+        {
+            return false;
+        }
+        match join_paths(cells, other_cells, self.size as usize) {
+            Some(joined) => {
+                puzzle.cages[index] = self.cage_from_cells(grid, joined, rng);
+                puzzle.cages.remove(other);
+                true
             }
-            MathOp::Sub => {
-                let (n, m) = (grid.0[cells[0]], grid.0[cells[1]]);
-                (max(n, m) - min(n, m)) as u32
+            None => false,
+        }
+    }
+}
+/// Cells orthogonally adjacent to `cell` in grid of `size`
+fn neighbors(cell: usize, size: usize) -> impl Iterator<Item = usize> {
+    [
+        cell.checked_sub(size),
+        (!cell.is_multiple_of(size)).then(|| cell - 1),
+        (cell % size + 1 < size).then_some(cell + 1),
+        (cell + size < size * size).then_some(cell + size),
+    ]
+    .into_iter()
+    .flatten()
+}
+/// Random partition of grid of `size` into `size` connected regions of `size` cells
+fn random_regions<R: Rng + ?Sized>(size: usize, rng: &mut R) -> Vec<Vec<usize>> {
+    'layout: loop {
+        let mut free = vec![true; size * size];
+        let mut regions = Vec::with_capacity(size);
+        for _ in 0..size {
+            //Growing from the first free cell keeps the rest of grid from being cut into pieces
+            let start = free.iter().position(|free| *free).unwrap();
+            free[start] = false;
+            let mut region = vec![start];
+            while region.len() < size {
+                let frontier: Vec<usize> = region
+                    .iter()
+                    .flat_map(|cell| neighbors(*cell, size))
+                    .filter(|cell| free[*cell])
+                    .collect();
+                let Some(&next) = frontier.choose(rng) else {
+                    continue 'layout;
+                };
+                free[next] = false;
+                region.push(next);
             }
-            MathOp::Mul => {
-                let mut product = 1;
-                for idx in &cells {
-                    product *= grid.0[*idx] as u32;
-                }
-                product
+            region.sort();
+            regions.push(region);
+        }
+        return regions;
+    }
+}
+/// Joins two paths of cells into one, if end of one is next to end of the other
+fn join_paths(a: &[usize], b: &[usize], size: usize) -> Option<Vec<usize>> {
+    let adjacent =
+        |x: usize, y: usize| (x / size == y / size && x.abs_diff(y) == 1) || x.abs_diff(y) == size;
+    let (a_start, a_end) = (a[0], a[a.len() - 1]);
+    let (b_start, b_end) = (b[0], b[b.len() - 1]);
+    let mut joined = Vec::with_capacity(a.len() + b.len());
+    if adjacent(a_end, b_start) {
+        joined.extend(a.iter().chain(b.iter()));
+    } else if adjacent(a_end, b_end) {
+        joined.extend(a.iter().chain(b.iter().rev()));
+    } else if adjacent(a_start, b_start) {
+        joined.extend(a.iter().rev().chain(b.iter()));
+    } else if adjacent(a_start, b_end) {
+        joined.extend(b.iter().chain(a.iter()));
+    } else {
+        return None;
+    }
+    Some(joined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_generation() {
+        let mut gen = KenkenGenerator::new(4, Difficulty::Any, 10, true, 3, None);
+        gen.seed = Some(2024);
+        let first = gen.generate_puzzles(3, true, None).unwrap();
+        let second = gen.generate_puzzles(3, true, None).unwrap();
+        for (a, b) in first.iter().zip(second.iter()) {
+            assert_eq!(a.format(), b.format());
+        }
+        let mut rng = ChaCha8Rng::seed_from_u64(2024);
+        let third = gen
+            .generate_puzzles_with_rng(3, true, None, &mut rng)
+            .unwrap();
+        let fourth = gen
+            .generate_puzzles_with_rng(3, true, None, &mut ChaCha8Rng::seed_from_u64(2024))
+            .unwrap();
+        assert_eq!(third[2].format(), fourth[2].format());
+    }
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_generation_is_deterministic() {
+        let mut gen = KenkenGenerator::new(5, Difficulty::Hard, 20, true, 4, None);
+        gen.seed = Some(99);
+        let run = |threads| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| gen.generate_puzzles(4, true, None).unwrap())
+        };
+        let single: Vec<String> = run(1).iter().map(|p| p.format()).collect();
+        let multi: Vec<String> = run(4).iter().map(|p| p.format()).collect();
+        assert_eq!(single, multi);
+    }
+    #[test]
+    fn puzzle_stream() {
+        let mut gen = KenkenGenerator::new(4, Difficulty::Medium, 10, true, 3, None);
+        gen.seed = Some(5);
+        let mut reports = 0;
+        let generated: Vec<GeneratedPuzzle> = gen
+            .puzzles(true, None)
+            .on_progress(|_| reports += 1)
+            .take(2)
+            .collect();
+        assert!(reports >= 2);
+        for g in &generated {
+            let solution = g.solution.as_ref().unwrap();
+            assert_eq!(solution.grid.0, g.grid.0);
+            assert_eq!(g.difficulty, Some(Difficulty::Medium));
+        }
+        let mut stream = gen.puzzles(true, None);
+        stream.nth(1);
+        let progress = stream.progress();
+        assert_eq!(progress.accepted, 2);
+        assert_eq!(
+            progress.attempts,
+            progress.accepted
+                + progress.no_solution
+                + progress.not_unique
+                + progress.wrong_difficulty
+                + progress.solver_limit
+        );
+    }
+    #[test]
+    fn generalized_generation() {
+        let mut gen = KenkenGenerator::new(
+            4,
+            Difficulty::Any,
+            20,
+            true,
+            4,
+            Some([0.2, 1.0, 0.2, 1.0, 0.1]),
+        );
+        gen.seed = Some(8);
+        gen.rules.generalized_sub_div = true;
+        let generated: Vec<GeneratedPuzzle> = gen.puzzles(true, None).take(5).collect();
+        assert!(generated.iter().flat_map(|g| &g.puzzle.cages).any(|cage| {
+            cage.cells.len() > 2 && matches!(cage.operation, MathOp::Sub | MathOp::Div)
+        }));
+        for g in generated {
+            assert!(g.puzzle.validate().is_ok());
+            assert_eq!(g.solution.unwrap().grid.0, g.grid.0);
+        }
+    }
+    #[test]
+    fn hidden_operations_generation() {
+        let mut gen = KenkenGenerator::new(4, Difficulty::Any, 20, true, 3, None);
+        gen.seed = Some(21);
+        gen.hide_operations = true;
+        gen.max_repairs = 50;
+        for g in gen.puzzles(true, None).take(3) {
+            for cage in &g.puzzle.cages {
+                assert_eq!(cage.operation == MathOp::Unknown, cage.cells.len() > 1);
             }
-            MathOp::Div => {
-                let (n, m) = (grid.0[cells[0]], grid.0[cells[1]]);
-                (max(n, m) / min(n, m)) as u32
+            let solutions = g.puzzle.solve(&20, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+            assert_eq!(solutions[0].grid.0, g.grid.0);
+        }
+    }
+    #[test]
+    fn extra_operations_generation() {
+        let mut gen = KenkenGenerator::new(4, Difficulty::Any, 20, true, 3, None);
+        gen.seed = Some(5);
+        gen.operation_weight[5..].copy_from_slice(&[1.0; 7]);
+        gen.max_repairs = 50;
+        for g in gen.puzzles(true, None).take(3) {
+            for cage in &g.puzzle.cages {
+                let values: Vec<u8> = cage.cells.iter().map(|i| g.grid.0[*i]).collect();
+                assert_eq!(cage.operation.evaluate(&values), Some(cage.target));
+            }
+            let solutions = g.puzzle.solve(&20, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+        }
+    }
+    #[test]
+    fn rejected_operations_fall_back() {
+        let mut gen = KenkenGenerator::new(4, Difficulty::Any, 20, true, 3, None);
+        gen.rules.generalized_sub_div = true;
+        gen.operation_weight = [0.0; 12];
+        gen.operation_weight[1] = 1.0;
+        gen.operation_weight[3] = 1.0;
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        //Neither 4 - 2 - 3 nor 4 / 2 / 3 has a result
+        let cage = gen.cage_from_cells(&Grid::new(4), vec![1, 2, 3], &mut rng);
+        assert_eq!((cage.operation, cage.target), (MathOp::Add, 9));
+        //3 - 2 - 1 is zero and 3 / 2 / 1 isn't whole
+        let cage = gen.cage_from_cells(&Grid::new(4), vec![0, 1, 2], &mut rng);
+        assert_eq!((cage.operation, cage.target), (MathOp::Add, 6));
+    }
+    #[test]
+    fn generation_without_free_operation() {
+        let mut gen = KenkenGenerator::new(
+            5,
+            Difficulty::Any,
+            20,
+            false,
+            2,
+            Some([1.0, 1.0, 1.0, 1.0, 0.0]),
+        );
+        let mut rng = ChaCha8Rng::seed_from_u64(17);
+        for _ in 0..20 {
+            //Cells left without free neighbor used to retry forever
+            let (puzzle, _) = gen.generate_puzzle(&mut rng).unwrap();
+            let mut cells: Vec<usize> = puzzle.cages.iter().flat_map(|c| c.cells.clone()).collect();
+            cells.sort();
+            assert_eq!(cells, (0..25).collect::<Vec<usize>>());
+        }
+        let token = CancellationToken::new();
+        token.cancel();
+        gen.limits.cancel = Some(token);
+        assert_eq!(
+            gen.generate_puzzle(&mut rng).unwrap_err(),
+            StopReason::Cancelled
+        );
+    }
+    #[test]
+    fn zero_based_generation() {
+        let mut gen = KenkenGenerator::new(4, Difficulty::Any, 20, true, 3, None);
+        gen.seed = Some(8);
+        gen.rules.min_digit = 0;
+        gen.max_repairs = 50;
+        for g in gen.puzzles(true, None).take(3) {
+            let mut digits = g.grid.0[..4].to_vec();
+            digits.sort();
+            assert_eq!(digits, vec![0, 1, 2, 3]);
+            let parsed = KenkenPuzzle::parse(&g.puzzle.format()).unwrap();
+            let solutions = parsed.solve(&20, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+            assert_eq!(solutions[0].grid.0, g.grid.0);
+        }
+        gen.rules.min_digit = 253; //largest digit 256 doesn't fit
+        let err = gen.generate_puzzles(1, true, None).unwrap_err();
+        assert_eq!(
+            err.reason,
+            StopReason::InvalidRules(PuzzleError::DigitsOutOfRange)
+        );
+        assert_eq!(err.attempts, 0);
+    }
+    #[test]
+    fn boxes_generation() {
+        let mut gen = KenkenGenerator::new(6, Difficulty::Any, 20, true, 3, None);
+        gen.seed = Some(4);
+        gen.rules.boxes = true;
+        gen.max_repairs = 50;
+        for g in gen.puzzles(true, None).take(2) {
+            for index in 0..6 {
+                let (top, left) = (index / 2 * 2, index % 2 * 3);
+                let mut digits: Vec<u8> = (0..6)
+                    .map(|i| g.grid.0[(top + i / 3) * 6 + left + i % 3])
+                    .collect();
+                digits.sort();
+                assert_eq!(digits, vec![1, 2, 3, 4, 5, 6]);
+            }
+            let solutions = g.puzzle.solve(&20, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+        }
+    }
+    #[test]
+    fn diagonals_generation() {
+        let mut gen = KenkenGenerator::new(5, Difficulty::Any, 20, true, 3, None);
+        gen.seed = Some(6);
+        gen.rules.diagonals = true;
+        gen.max_repairs = 50;
+        for g in gen.puzzles(true, None).take(2) {
+            let mut main: Vec<u8> = (0..5).map(|i| g.grid.0[i * 6]).collect();
+            let mut anti: Vec<u8> = (0..5).map(|i| g.grid.0[i * 4 + 4]).collect();
+            main.sort();
+            anti.sort();
+            assert_eq!(main, vec![1, 2, 3, 4, 5]);
+            assert_eq!(anti, main);
+            let solutions = g.puzzle.solve(&20, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+        }
+    }
+    #[test]
+    fn inequalities_generation() {
+        let mut gen = KenkenGenerator::new(6, Difficulty::Any, 20, true, 4, None);
+        gen.seed = Some(10);
+        gen.inequalities = true;
+        gen.max_repairs = 50;
+        let generated: Vec<GeneratedPuzzle> = gen.puzzles(true, None).take(3).collect();
+        assert!(generated.iter().any(|g| !g.puzzle.inequalities.is_empty()));
+        for g in generated {
+            for inequality in &g.puzzle.inequalities {
+                assert!(g.grid.0[inequality.greater] > g.grid.0[inequality.less]);
+            }
+            assert_eq!(g.puzzle.validate(), Ok(()));
+            let parsed = KenkenPuzzle::parse(&g.puzzle.format()).unwrap();
+            let solutions = parsed.solve(&20, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+        }
+    }
+    #[test]
+    fn inequality_replaces_cage() {
+        let mut gen = KenkenGenerator::new(5, Difficulty::Any, 20, true, 4, None);
+        gen.inequalities = true;
+        let mut rng = ChaCha8Rng::seed_from_u64(5);
+        let (puzzle, grid, ambiguity) = loop {
+            let (puzzle, grid) = gen.generate_puzzle(&mut rng).unwrap();
+            if let Some(ambiguity) = puzzle.ambiguity(&SolverOptions::default()).unwrap() {
+                break (puzzle, grid, ambiguity);
             }
-            MathOp::Free => grid.0[cells[0]] as u32,
-        };
-        return Cage {
-            target,
-            operation,
-            cells,
         };
+        let mut swapped = false;
+        for _ in 0..50 {
+            let mut repaired = puzzle.clone();
+            gen.repair_puzzle(&mut repaired, &grid, &ambiguity, &mut rng);
+            assert_eq!(repaired.validate(), Ok(()));
+            assert!(repaired.solutions().any(|s| s.grid.0 == grid.0));
+            swapped |=
+                repaired.inequalities.len() == 1 && repaired.cages.len() < puzzle.cages.len();
+        }
+        assert!(swapped);
+    }
+    #[test]
+    fn distinct_cages_generation() {
+        let mut gen = KenkenGenerator::new(5, Difficulty::Any, 20, true, 4, None);
+        gen.seed = Some(12);
+        gen.rules.distinct_cages = true;
+        gen.max_repairs = 50;
+        for g in gen.puzzles(true, None).take(3) {
+            for cage in &g.puzzle.cages {
+                let mut digits: Vec<u8> = cage.cells.iter().map(|i| g.grid.0[*i]).collect();
+                digits.sort();
+                digits.dedup();
+                assert_eq!(digits.len(), cage.cells.len());
+            }
+            let solutions = g.puzzle.solve(&20, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+        }
+    }
+    #[test]
+    fn regions_generation() {
+        let mut rng = ChaCha8Rng::seed_from_u64(13);
+        for size in [5, 7, 9] {
+            let regions = random_regions(size, &mut rng);
+            let mut cells: Vec<usize> = regions.concat();
+            cells.sort();
+            assert_eq!(cells, (0..size * size).collect::<Vec<usize>>());
+            assert!(regions.iter().all(|region| region.len() == size));
+        }
+        let mut gen = KenkenGenerator::new(6, Difficulty::Any, 20, true, 4, None);
+        gen.seed = Some(14);
+        gen.regions = true;
+        gen.max_repairs = 50;
+        for g in gen.puzzles(true, None).take(2) {
+            assert_eq!(g.puzzle.validate(), Ok(()));
+            for region in &g.puzzle.regions {
+                let mut digits: Vec<u8> = region.iter().map(|cell| g.grid.0[*cell]).collect();
+                digits.sort();
+                assert_eq!(digits, vec![1, 2, 3, 4, 5, 6]);
+            }
+            let parsed = KenkenPuzzle::parse(&g.puzzle.format()).unwrap();
+            let solutions = parsed.solve(&20, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+        }
+    }
+    #[test]
+    fn generation_with_givens() {
+        let mut gen = KenkenGenerator::new(5, Difficulty::Any, 20, true, 4, None);
+        gen.seed = Some(3);
+        gen.givens = 4;
+        for g in gen.puzzles(true, None).take(2) {
+            assert_eq!(g.puzzle.givens.len(), 4);
+            for (cell, digit) in &g.puzzle.givens {
+                assert_eq!(g.grid.0[*cell], *digit);
+            }
+            let parsed = KenkenPuzzle::parse(&g.puzzle.format()).unwrap();
+            assert_eq!(parsed.givens, g.puzzle.givens);
+        }
+    }
+    #[test]
+    fn repaired_generation() {
+        let mut gen = KenkenGenerator::new(5, Difficulty::Any, 20, true, 4, None);
+        gen.seed = Some(11);
+        gen.max_repairs = 50;
+        let mut stream = gen.puzzles(true, None);
+        let generated: Vec<GeneratedPuzzle> = stream.by_ref().take(5).collect();
+        assert!(stream.progress().repaired > 0);
+        for g in generated {
+            let solutions = g.puzzle.solve(&20, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+            assert_eq!(solutions[0].grid.0, g.grid.0);
+            let mut cells: Vec<usize> = g
+                .puzzle
+                .cages
+                .iter()
+                .flat_map(|c| c.cells.clone())
+                .collect();
+            cells.sort();
+            assert_eq!(cells, (0..25).collect::<Vec<usize>>());
+        }
+    }
+    #[test]
+    fn bounded_generation() {
+        //Single cell cages can't make extreme puzzle
+        let mut gen = KenkenGenerator::new(4, Difficulty::Extreme, 10, true, 1, None);
+        gen.limits.max_attempts = Some(5);
+        let err = gen.generate_puzzles(1, true, None).unwrap_err();
+        assert_eq!(err.reason, StopReason::AttemptsExhausted);
+        assert_eq!(err.attempts, 5);
+        assert!(err.puzzles.is_empty());
+
+        gen.limits.max_attempts = None;
+        let token = CancellationToken::new();
+        token.cancel();
+        gen.limits.cancel = Some(token);
+        let err = gen.generate_puzzles(1, true, None).unwrap_err();
+        assert_eq!(err.reason, StopReason::Cancelled);
+
+        gen.limits.cancel = None;
+        gen.limits.deadline = Some(Instant::now());
+        let err = gen.generate_puzzles(1, true, None).unwrap_err();
+        assert_eq!(err.reason, StopReason::DeadlineExceeded);
     }
 }
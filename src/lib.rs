@@ -1,7 +1,9 @@
-// TODO: Optimise, Logger, Rayon::scope, documentation, Serde serialization
+// TODO: Optimise, Logger, documentation, Serde serialization
 
-use rand::{distributions::Uniform, Rng};
+use rand::{distributions::Uniform, seq::SliceRandom, Rng};
+use std::ops::RangeInclusive;
 
+pub mod daily;
 pub mod generator;
 pub mod solver;
 
@@ -21,12 +23,133 @@ impl Grid {
 
         Self(grid, size)
     }
+    /// Samples Latin square uniformly at random with Jacobson–Matthews Markov chain.
+    /// Unlike `new` + `shuffle`, any valid grid can be returned, including relabeled digits.
+    pub fn random<R: Rng + ?Sized>(size: u8, rng: &mut R) -> Self {
+        let n = size as usize;
+        if n < 2 {
+            return Self::new(size);
+        }
+        //Incidence cube: cube[(row * n + col) * n + digit] is 1 when digit is at (row, col).
+        //Improper squares have exactly one -1 entry.
+        let idx = |x: usize, y: usize, z: usize| (x * n + y) * n + z;
+        let mut cube = vec![0i8; n * n * n];
+        let start = Self::new(size);
+        for (i, value) in start.0.iter().enumerate() {
+            cube[i * n + *value as usize - 1] = 1;
+        }
+        let mut improper: Option<(usize, usize, usize)> = None;
+        let mut steps = 0;
+        let min_steps = n * n * n;
+        while steps < min_steps || improper.is_some() {
+            steps += 1;
+            let (x, y, z, x1, y1, z1);
+            if let Some((ix, iy, iz)) = improper {
+                (x, y, z) = (ix, iy, iz);
+                //Choose randomly one of two positive entries on each line through the improper cell
+                let pick = |cells: Vec<usize>, rng: &mut R| cells[rng.gen_range(0..cells.len())];
+                x1 = pick((0..n).filter(|i| cube[idx(*i, y, z)] == 1).collect(), rng);
+                y1 = pick((0..n).filter(|i| cube[idx(x, *i, z)] == 1).collect(), rng);
+                z1 = pick((0..n).filter(|i| cube[idx(x, y, *i)] == 1).collect(), rng);
+            } else {
+                loop {
+                    let (a, b, c) = (
+                        rng.gen_range(0..n),
+                        rng.gen_range(0..n),
+                        rng.gen_range(0..n),
+                    );
+                    if cube[idx(a, b, c)] == 0 {
+                        (x, y, z) = (a, b, c);
+                        break;
+                    }
+                }
+                x1 = (0..n).find(|i| cube[idx(*i, y, z)] == 1).unwrap();
+                y1 = (0..n).find(|i| cube[idx(x, *i, z)] == 1).unwrap();
+                z1 = (0..n).find(|i| cube[idx(x, y, *i)] == 1).unwrap();
+            }
+            cube[idx(x, y, z)] += 1;
+            cube[idx(x, y1, z1)] += 1;
+            cube[idx(x1, y, z1)] += 1;
+            cube[idx(x1, y1, z)] += 1;
+            cube[idx(x, y, z1)] -= 1;
+            cube[idx(x, y1, z)] -= 1;
+            cube[idx(x1, y, z)] -= 1;
+            cube[idx(x1, y1, z1)] -= 1;
+            improper = if cube[idx(x1, y1, z1)] < 0 {
+                Some((x1, y1, z1))
+            } else {
+                None
+            };
+        }
+
+        let mut grid = Vec::with_capacity(n * n);
+        for cell in 0..n * n {
+            let digit = (0..n).find(|z| cube[cell * n + z] == 1).unwrap();
+            grid.push(digit as u8 + 1);
+        }
+        Self(grid, size)
+    }
+    /// Samples grid by randomized backtracking, where cells for which `peers` is true hold different digits.
+    /// `peers` should include rows and columns.
+    /// Returns `None` if no such grid exists or it wasn't found in `max_nodes` tried digits.
+    pub fn random_with<R: Rng + ?Sized, F: Fn(usize, usize) -> bool>(
+        size: u8,
+        rng: &mut R,
+        peers: F,
+        max_nodes: Option<u64>,
+    ) -> Option<Self> {
+        let n = size as usize * size as usize;
+        let peers: Vec<Vec<usize>> = (0..n)
+            .map(|a| (0..n).filter(|b| *b != a && peers(a, *b)).collect())
+            .collect();
+        let mut grid = vec![0; n];
+        let mut nodes = max_nodes.unwrap_or(u64::MAX);
+        Self::fill(&mut grid, &peers, size, &mut nodes, rng).then_some(Self(grid, size))
+    }
+    /// Fills empty (zero) cells of `grid`, always the one with fewest options first
+    fn fill<R: Rng + ?Sized>(
+        grid: &mut [u8],
+        peers: &[Vec<usize>],
+        size: u8,
+        nodes: &mut u64,
+        rng: &mut R,
+    ) -> bool {
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for cell in (0..grid.len()).filter(|cell| grid[*cell] == 0) {
+            let options: Vec<u8> = (1..=size)
+                .filter(|digit| peers[cell].iter().all(|peer| grid[*peer] != *digit))
+                .collect();
+            if best
+                .as_ref()
+                .is_none_or(|(_, best)| options.len() < best.len())
+            {
+                let dead_end = options.is_empty();
+                best = Some((cell, options));
+                if dead_end {
+                    break;
+                }
+            }
+        }
+        let Some((cell, mut options)) = best else {
+            return true;
+        };
+        options.shuffle(rng);
+        for digit in options {
+            if *nodes == 0 {
+                break;
+            }
+            *nodes -= 1;
+            grid[cell] = digit;
+            if Self::fill(grid, peers, size, nodes, rng) {
+                return true;
+            }
+        }
+        grid[cell] = 0;
+        false
+    }
     pub fn print(&self) {
         let size = self.1 as usize;
-        let mut offset = 0;
-        if size > 9 {
-            offset = 1;
-        }
+        let offset = self.0.iter().max().map_or(1, |max| max.to_string().len()) - 1;
         println!("/{:-^1$}\\", "", (2 + offset) * size + 1);
         for i in (0..self.0.len()).step_by(size) {
             let row = &self.0[i..i + size];
@@ -38,8 +161,18 @@ impl Grid {
         }
         println!("\\{:-^1$}/", "", (2 + offset) * size + 1);
     }
+    /// Relabels digits to run from `min_digit` instead of 1, see `Rules::min_digit`
+    pub fn shift_digits(&mut self, min_digit: u8) {
+        let first = self.0.iter().min().copied().unwrap_or(min_digit);
+        for value in self.0.iter_mut() {
+            *value = *value - first + min_digit;
+        }
+    }
     pub fn shuffle(&mut self, count: u32) {
-        let mut rng = rand::thread_rng();
+        self.shuffle_with_rng(count, &mut rand::thread_rng());
+    }
+    /// Same as `shuffle`, but draws randomness from `rng`, so a seeded `rng` gives reproducible grids
+    pub fn shuffle_with_rng<R: Rng + ?Sized>(&mut self, count: u32, rng: &mut R) {
         let uni = Uniform::new(0, self.1);
         for _ in 0..count {
             self.swap_row(rng.sample(uni), rng.sample(uni));
@@ -79,19 +212,235 @@ impl Grid {
 }
 
 /// Variants of operations in KenKen cage
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum MathOp {
     Add,
     Sub,
     Mul,
     Div,
     Free,
+    /// Larger of two digits modulo the smaller
+    Mod,
+    /// Larger of two digits raised to the smaller
+    Pow,
+    Min,
+    Max,
+    /// Greatest common divisor
+    Gcd,
+    /// Least common multiple
+    Lcm,
+    /// Digits written one after another in order of `Cage::cells`
+    Concat,
+    /// Operation hidden from player, any of `MathOp::CLASSIC` which fits the cage can give the target
+    Unknown,
+}
+impl MathOp {
+    /// Operations of classic KenKen, `Unknown` stands for one of them
+    pub const CLASSIC: [MathOp; 5] = [
+        MathOp::Add,
+        MathOp::Sub,
+        MathOp::Mul,
+        MathOp::Div,
+        MathOp::Free,
+    ];
+    /// Operations which can be shown in a cage
+    pub const KNOWN: [MathOp; 12] = [
+        MathOp::Add,
+        MathOp::Sub,
+        MathOp::Mul,
+        MathOp::Div,
+        MathOp::Free,
+        MathOp::Mod,
+        MathOp::Pow,
+        MathOp::Min,
+        MathOp::Max,
+        MathOp::Gcd,
+        MathOp::Lcm,
+        MathOp::Concat,
+    ];
+    /// Target of cage with this operation containing `values`, `None` if the operation can't give these values.
+    /// `Sub` and `Div` take the largest value minus or divided by all the others, but zero divided by the others gives 0.
+    /// `Unknown` has no single target.
+    pub fn evaluate(&self, values: &[u8]) -> Option<u32> {
+        match (self, values) {
+            (MathOp::Add, _) => Some(values.iter().map(|v| *v as u32).sum()),
+            (MathOp::Mul, _) => values
+                .iter()
+                .try_fold(1u32, |product, v| product.checked_mul(*v as u32)),
+            (MathOp::Sub | MathOp::Div, [_, _, ..]) => {
+                let mut sorted = values.to_vec();
+                sorted.sort();
+                let (largest, rest) = sorted.split_last().unwrap();
+                let largest = *largest as u32;
+                if *self == MathOp::Sub {
+                    largest.checked_sub(rest.iter().map(|v| *v as u32).sum())
+                } else if sorted[0] == 0 {
+                    //Only one zero, nothing can be divided by it
+                    (sorted[1] != 0).then_some(0)
+                } else {
+                    let divisor = rest
+                        .iter()
+                        .try_fold(1u32, |product, v| product.checked_mul(*v as u32))?;
+                    (divisor != 0 && largest.is_multiple_of(divisor)).then(|| largest / divisor)
+                }
+            }
+            (MathOp::Free, [a]) => Some(*a as u32),
+            (MathOp::Mod, [a, b]) => {
+                let (a, b) = (*a.max(b) as u32, *a.min(b) as u32);
+                (b != 0).then(|| a % b)
+            }
+            (MathOp::Pow, [a, b]) => (*a.max(b) as u32).checked_pow(*a.min(b) as u32),
+            (MathOp::Min, _) => values.iter().min().map(|v| *v as u32),
+            (MathOp::Max, _) => values.iter().max().map(|v| *v as u32),
+            (MathOp::Gcd, [_, ..]) => Some(values.iter().fold(0, |g, v| gcd(g, *v as u32))),
+            (MathOp::Lcm, [_, ..]) => values.iter().try_fold(1u32, |l, v| {
+                let v = *v as u32;
+                match v {
+                    0 => Some(0),
+                    _ => (l / gcd(l, v).max(1)).checked_mul(v),
+                }
+            }),
+            (MathOp::Concat, [_, ..]) => values.iter().try_fold(0u32, |number, v| {
+                let shift = 10u32.pow(v.to_string().len() as u32);
+                number.checked_mul(shift)?.checked_add(*v as u32)
+            }),
+            _ => None,
+        }
+    }
+    /// Whether cage of `len` cells can have this operation under `rules`
+    pub fn fits(&self, len: usize, rules: &Rules) -> bool {
+        match self {
+            MathOp::Add | MathOp::Mul => len > 0,
+            MathOp::Sub | MathOp::Div => len == 2 || (rules.generalized_sub_div && len > 2),
+            MathOp::Free => len == 1,
+            MathOp::Mod | MathOp::Pow => len == 2,
+            MathOp::Min | MathOp::Max | MathOp::Gcd | MathOp::Lcm | MathOp::Concat => len > 0,
+            MathOp::Unknown => len > 0,
+        }
+    }
+}
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+/// Rule variants of puzzle, default is classic KenKen
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rules {
+    /// `MathOp::Sub` and `MathOp::Div` cages can have more than two cells,
+    /// target is the largest digit minus or divided by all the others
+    pub generalized_sub_div: bool,
+    /// Smallest digit of the grid, digits are `min_digit..min_digit + size`. Zero-based KenKen has 0.
+    pub min_digit: u8,
+    /// Every rectangular box must contain each digit once too (KenDoku), see `Rules::box_shape`
+    pub boxes: bool,
+    /// Both main diagonals must contain distinct digits. There is no such grid of size 2 or 3.
+    pub diagonals: bool,
+    /// Digits in a cage can't repeat even in different rows and columns, as in Killer Sudoku
+    pub distinct_cages: bool,
+}
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            generalized_sub_div: false,
+            min_digit: 1,
+            boxes: false,
+            diagonals: false,
+            distinct_cages: false,
+        }
+    }
+}
+impl Rules {
+    /// Digits which fill grid of `size`
+    pub fn digits(&self, size: u8) -> RangeInclusive<u8> {
+        self.min_digit..=self.min_digit.saturating_add(size.saturating_sub(1))
+    }
+    /// Rows and columns of one box in grid of `size`, as close to square as possible.
+    /// `None` without `boxes` or when `size` can't be split, e.g. for primes.
+    pub fn box_shape(&self, size: u8) -> Option<(usize, usize)> {
+        let size = size as usize;
+        if !self.boxes {
+            return None;
+        }
+        let rows = (2..size)
+            .take_while(|rows| rows * rows <= size)
+            .filter(|rows| size.is_multiple_of(*rows))
+            .last()?;
+        Some((rows, size / rows))
+    }
 }
 /// Data type for KenKen cage
 #[derive(Clone, Debug)]
 pub struct Cage {
     pub target: u32,
     pub operation: MathOp,
-    ///indexes, Need to be ordered start-end or end-start
+    ///indexes in any order, generated cages are ordered start-end
     pub cells: Vec<usize>,
 }
+/// Futoshiki-style relation of two orthogonally adjacent cells, digit in `greater` is larger than digit in `less`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Inequality {
+    pub greater: usize,
+    pub less: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    fn is_latin(grid: &Grid) -> bool {
+        let n = grid.1 as usize;
+        (0..n).all(|i| {
+            let mut row: Vec<u8> = grid.0[i * n..(i + 1) * n].to_vec();
+            let mut col: Vec<u8> = (0..n).map(|j| grid.0[j * n + i]).collect();
+            row.sort();
+            col.sort();
+            let digits: Vec<u8> = (1..=grid.1).collect();
+            row == digits && col == digits
+        })
+    }
+    #[test]
+    fn random_grid() {
+        let mut rng = ChaCha8Rng::seed_from_u64(7);
+        for size in 1..=9 {
+            assert!(is_latin(&Grid::random(size, &mut rng)));
+        }
+        //All 12 Latin squares of size 3 should show up
+        let mut seen: Vec<Vec<u8>> = Vec::new();
+        for _ in 0..500 {
+            let grid = Grid::random(3, &mut rng);
+            if !seen.contains(&grid.0) {
+                seen.push(grid.0);
+            }
+        }
+        assert_eq!(seen.len(), 12);
+    }
+    #[test]
+    fn random_diagonal_grid() {
+        let mut rng = ChaCha8Rng::seed_from_u64(9);
+        let peers = |n: usize| {
+            let main = move |cell: usize| cell / n == cell % n;
+            let anti = move |cell: usize| cell / n + cell % n == n - 1;
+            move |a: usize, b: usize| {
+                a / n == b / n || a % n == b % n || (main(a) && main(b)) || (anti(a) && anti(b))
+            }
+        };
+        for size in 4..=9 {
+            let n = size as usize;
+            let grid = Grid::random_with(size, &mut rng, peers(n), None).unwrap();
+            assert!(is_latin(&grid));
+            let mut main: Vec<u8> = (0..n).map(|i| grid.0[i * n + i]).collect();
+            let mut anti: Vec<u8> = (0..n).map(|i| grid.0[i * n + n - 1 - i]).collect();
+            main.sort();
+            anti.sort();
+            assert_eq!(main, (1..=size).collect::<Vec<u8>>());
+            assert_eq!(anti, main);
+        }
+        assert!(Grid::random_with(3, &mut rng, peers(3), None).is_none());
+        assert!(Grid::random_with(9, &mut rng, peers(9), Some(10)).is_none());
+    }
+}
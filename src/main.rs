@@ -1,10 +1,12 @@
 #[rustfmt::skip]
 fn main() {
     println!("Kenken solver:");
-    use kenken::solver::*;
     use kenken::generator::*;
     let gen = KenkenGenerator::new(6, Difficulty::Extreme, 24, true, 5, None);
-    println!("{:?}",gen.generate_puzzles(1, true, None)[0]);
+    match gen.generate_puzzles(1, true, None) {
+        Ok(puzzles) => println!("{:?}",puzzles[0]),
+        Err(error) => eprintln!("Generation stopped: {:?} after {} attempts", error.reason, error.attempts),
+    }
 
 
 }
@@ -1,11 +1,15 @@
-use super::{Cage, Grid, MathOp};
+use super::{Cage, Grid, Inequality, MathOp, Rules};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Stores grid and depth where the solution was found
 #[derive(Clone, Debug)]
 pub struct Solution {
     /// 'grid' contains found solution
     pub grid: Grid,
-    /// `depth` counts recursion cycles which occurs when guessing solutions is needed
+    /// `depth` counts nested guesses needed to reach the solution
     pub depth: usize,
 }
 impl Solution {
@@ -17,14 +21,14 @@ impl Solution {
                 Cell::Possible(_) => panic!("Area is unsolved"),
             }
         }
-        return Self {
+        Self {
             grid: Grid(solved, size),
             depth,
-        };
+        }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Cell {
     Solution(u8),
     Possible(Vec<u8>),
@@ -33,55 +37,22 @@ type Area = Vec<Cell>; //I am NOT changing this to tuple struct
                        //EDIT: okay i will rewrite this one day.....
 
 trait ApplyPossibilities {
-    fn apply_sequences(&mut self, sequences: &Vec<Vec<u8>>);
-    fn apply_pairs(&mut self, pairs: &Vec<(u8, u8)>);
+    fn apply_sequences(&mut self, sequences: &[Vec<u8>]);
 }
 trait BestCandidate {
     fn get_best_candidate(&self) -> Option<(usize, usize)>;
 }
 impl ApplyPossibilities for Area {
-    fn apply_sequences(&mut self, sequences: &Vec<Vec<u8>>) {
-        //Add, Mul
+    fn apply_sequences(&mut self, sequences: &[Vec<u8>]) {
         for (e, cell) in self.iter_mut().enumerate() {
-            match cell {
-                Cell::Possible(v) => {
-                    v.clear();
-                    for seq in sequences.iter() {
-                        if !v.contains(&seq[e]) {
-                            v.push(seq[e]);
-                        }
-                    }
-                }
-                _ => (),
-            }
-        }
-    }
-    fn apply_pairs(&mut self, pairs: &Vec<(u8, u8)>) {
-        //Div, Sub
-        if self.len() != 2 {
-            panic!("Area needs to have just 2 cells");
-        }
-        match &mut self[0] {
-            Cell::Possible(v) => {
+            if let Cell::Possible(v) = cell {
                 v.clear();
-                for pair in pairs {
-                    if !v.contains(&pair.0) {
-                        v.push(pair.0);
+                for seq in sequences.iter() {
+                    if !v.contains(&seq[e]) {
+                        v.push(seq[e]);
                     }
                 }
             }
-            _ => (),
-        }
-        match &mut self[1] {
-            Cell::Possible(v) => {
-                v.clear();
-                for pair in pairs {
-                    if !v.contains(&pair.1) {
-                        v.push(pair.1);
-                    }
-                }
-            }
-            _ => (),
         }
     }
 }
@@ -89,63 +60,162 @@ impl BestCandidate for Area {
     fn get_best_candidate(&self) -> Option<(usize, usize)> {
         let mut best_candidate: Option<(usize, usize)> = None; //len, index
         for (i, cell) in self.iter().enumerate() {
-            match cell {
-                Cell::Possible(v) => {
-                    if let Some(x) = best_candidate {
-                        if x.0 > v.len() {
-                            best_candidate = Some((v.len(), i));
-                        }
-                    } else {
+            if let Cell::Possible(v) = cell {
+                if let Some(x) = best_candidate {
+                    if x.0 > v.len() {
                         best_candidate = Some((v.len(), i));
                     }
+                } else {
+                    best_candidate = Some((v.len(), i));
                 }
-                _ => (),
             }
         }
-        return best_candidate;
+        best_candidate
     }
 }
 
 impl Cage {
-    fn solve(&self, area: &mut Area, size: u8) {
+    /// Narrows possibilities of cage cells, returns `false` if no combination fits the cage.
+    /// `conflicts[i]` lists earlier positions in `cells` which can't hold the same digit as position `i`.
+    fn solve(&self, area: &mut Area, size: u8, conflicts: &[Vec<usize>], rules: &Rules) -> bool {
+        let seq = self.sequences(self.operation, area, size, conflicts, rules);
+        area.apply_sequences(&seq);
+        !seq.is_empty()
+    }
+    /// Every combination of digits in `area` which gives cage target with `operation`
+    fn sequences(
+        &self,
+        operation: MathOp,
+        area: &Area,
+        size: u8,
+        conflicts: &[Vec<usize>],
+        rules: &Rules,
+    ) -> Vec<Vec<u8>> {
         let len = area.len();
-        match self.operation {
-            MathOp::Add => {
-                let seq = sequence_gen::generate_sequences_sum(len, size, self.target, Some(area));
-                area.apply_sequences(&seq);
-            }
-            MathOp::Mul => {
-                let seq = sequence_gen::generate_sequences_mul(len, size, self.target, Some(area));
-                area.apply_sequences(&seq);
+        let digits = rules.digits(size);
+        let pairs = |pairs: Vec<(u8, u8)>| pairs.into_iter().map(|(a, b)| vec![a, b]).collect();
+        match operation {
+            MathOp::Sub | MathOp::Div if len > 2 && rules.generalized_sub_div => {
+                sequence_gen::generate_sequences_with(
+                    len,
+                    digits,
+                    Some(area),
+                    conflicts,
+                    &|_, _| true,
+                    &|seq| operation.evaluate(seq) == Some(self.target),
+                )
             }
+            MathOp::Add => sequence_gen::generate_sequences_sum(
+                len,
+                digits,
+                self.target,
+                Some(area),
+                conflicts,
+            ),
+            MathOp::Mul => sequence_gen::generate_sequences_mul(
+                len,
+                digits,
+                self.target,
+                Some(area),
+                conflicts,
+            ),
             MathOp::Div => {
                 if len != 2 {
                     panic!(
-                        "Division can't be applied to {len} cells. Only 2-cell cage can divide."
+                        "Division can't be applied to {len} cells. Only 2-cell cage can divide, unless sub and div are generalized."
                     );
                 }
-                let a = Some((area.get(0).unwrap(), area.get(1).unwrap()));
-                let seq = sequence_gen::generate_sequences_div(size, self.target, a);
-                area.apply_pairs(&seq);
+                let a = Some((area.first().unwrap(), area.get(1).unwrap()));
+                let distinct = conflicts[1].contains(&0);
+                pairs(sequence_gen::generate_sequences_div(
+                    digits,
+                    self.target,
+                    a,
+                    distinct,
+                ))
             }
             MathOp::Sub => {
                 if len != 2 {
-                    panic!("Subtraction can't be applied to {len} cells. Only 2-cell cage can subtract.");
+                    panic!("Subtraction can't be applied to {len} cells. Only 2-cell cage can subtract, unless sub and div are generalized.");
                 }
-                let a = Some((area.get(0).unwrap(), area.get(1).unwrap()));
-                let seq = sequence_gen::generate_sequences_sub(size, self.target, a);
-                area.apply_pairs(&seq);
+                let a = Some((area.first().unwrap(), area.get(1).unwrap()));
+                let distinct = conflicts[1].contains(&0);
+                pairs(sequence_gen::generate_sequences_sub(
+                    digits,
+                    self.target,
+                    a,
+                    distinct,
+                ))
             }
             MathOp::Free => {
                 if len != 1 {
                     panic!("Free cage can't be applied to {len} cells. Only 1-cell cage can be a freebie.");
                 }
-                let cell = &mut area[0];
-                if let Cell::Possible(v) = cell {
-                    v.clear();
-                    v.push(self.target as u8);
+                let fits = match &area[0] {
+                    Cell::Possible(v) => v.iter().any(|x| *x as u32 == self.target),
+                    Cell::Solution(n) => *n as u32 == self.target,
+                };
+                if fits {
+                    vec![vec![self.target as u8]]
+                } else {
+                    vec![]
                 }
             }
+            MathOp::Mod
+            | MathOp::Pow
+            | MathOp::Min
+            | MathOp::Max
+            | MathOp::Gcd
+            | MathOp::Lcm
+            | MathOp::Concat => {
+                if !operation.fits(len, rules) {
+                    panic!("{operation:?} can't be applied to {len} cells.");
+                }
+                let target = self.target;
+                //Leading zeros are hidden in target
+                let decimal: Vec<u8> = format!("{target:0len$}")
+                    .bytes()
+                    .map(|b| b - b'0')
+                    .collect();
+                let single_digits = *digits.end() <= 9;
+                let allowed = |position: usize, num: u8| {
+                    let num = num as u32;
+                    match operation {
+                        MathOp::Min => num >= target,
+                        MathOp::Max => num <= target,
+                        MathOp::Gcd if target == 0 => num == 0,
+                        MathOp::Gcd => num.is_multiple_of(target),
+                        MathOp::Lcm => target == 0 || (num != 0 && target.is_multiple_of(num)),
+                        //Every cell holds one digit of target
+                        MathOp::Concat if single_digits => {
+                            decimal.get(position) == Some(&(num as u8))
+                        }
+                        _ => true,
+                    }
+                };
+                sequence_gen::generate_sequences_with(
+                    len,
+                    digits,
+                    Some(area),
+                    conflicts,
+                    &allowed,
+                    &|seq| operation.evaluate(seq) == Some(target),
+                )
+            }
+            MathOp::Unknown => {
+                //Hidden operation can be any classic one which fits the cage
+                let mut sequences: Vec<Vec<u8>> = vec![];
+                for operation in MathOp::CLASSIC {
+                    if operation.fits(len, rules) {
+                        for seq in self.sequences(operation, area, size, conflicts, rules) {
+                            if !sequences.contains(&seq) {
+                                sequences.push(seq);
+                            }
+                        }
+                    }
+                }
+                sequences
+            }
         }
     }
 }
@@ -158,11 +228,406 @@ macro_rules! unwrap_or_return {
         }
     };
 }
-/// Defines solver errors
-#[derive(Clone, Copy, Debug)]
+/// Shared flag to stop running generation or search, e.g. from another thread
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+impl PartialEq for CancellationToken {
+    /// Clones of one token are equal
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+impl Eq for CancellationToken {}
+/// Limits for `KenkenPuzzle::solve_with_options`, `None` means unlimited
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SolverOptions {
+    /// Maximum number of nested guesses. Ruling out a guessed possibility doesn't go deeper.
+    pub max_depth: Option<usize>,
+    /// Maximum number of search nodes, every guess and every ruled out guess is a node
+    pub max_nodes: Option<u64>,
+    /// Maximum wall-clock time of search
+    pub time_limit: Option<Duration>,
+    /// Stops search early once enough solutions are found
+    pub max_solutions: Option<usize>,
+    /// Search fails with `SolverError::Cancelled` once this token is cancelled
+    pub cancel: Option<CancellationToken>,
+}
+/// Shared between branches of one search, so they can stop early
+struct SearchState {
+    options: SolverOptions,
+    deadline: Option<Instant>,
+    max_solutions: usize,
+    /// Constraints left out of search, used by `KenkenPuzzle::diagnose`
+    ignored: Vec<Constraint>,
+}
+impl SearchState {
+    fn new(options: &SolverOptions) -> Self {
+        Self {
+            options: options.clone(),
+            deadline: options.time_limit.map(|limit| Instant::now() + limit),
+            max_solutions: options.max_solutions.unwrap_or(0),
+            ignored: Vec::new(),
+        }
+    }
+    /// Counts new node at `depth`, the `local`-th one of branch at `position`, and checks limits
+    fn visit(&self, depth: usize, position: &Position, local: u64) -> Result<(), SolverError> {
+        let mut error = None;
+        if let Some(max_depth) = self.options.max_depth {
+            if depth > max_depth {
+                error = Some(SolverError::DepthExceeded { max_depth });
+            }
+        }
+        if let Some(max_nodes) = self.options.max_nodes {
+            //Earlier branches still running add more nodes, so this is the lowest possible index of the node.
+            //Exact index is known only when branches are merged, see `SearchState::result`.
+            let earlier: u64 = position
+                .iter()
+                .filter(|(_, first)| !first)
+                .map(|(split, _)| split.nodes.load(Ordering::Relaxed))
+                .sum();
+            if position.len() as u64 + earlier + local > max_nodes {
+                error = Some(SolverError::NodeLimitExceeded { max_nodes });
+            }
+            for (split, first) in position {
+                if *first {
+                    split.nodes.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if let (Some(deadline), Some(time_limit)) = (self.deadline, self.options.time_limit) {
+            if Instant::now() >= deadline {
+                error = Some(SolverError::TimeLimitExceeded { time_limit });
+            }
+        }
+        if let Some(token) = &self.options.cancel {
+            if token.is_cancelled() {
+                error = Some(SolverError::Cancelled);
+            }
+        }
+        match error {
+            Some(error) => {
+                //Branches later in search order are pointless
+                for (split, first) in position {
+                    if *first {
+                        split.failed.store(true, Ordering::Relaxed);
+                    }
+                }
+                Err(error)
+            }
+            None => Ok(()),
+        }
+    }
+    /// Whether branch at `position`, which found `found` solutions itself, can stop.
+    /// Only solutions and failures earlier in search order count, so the result doesn't depend on which branch
+    /// finishes first.
+    fn is_done(&self, position: &Position, found: usize) -> bool {
+        if position
+            .iter()
+            .any(|(split, first)| !first && split.failed.load(Ordering::Relaxed))
+        {
+            return true;
+        }
+        if self.max_solutions == 0 {
+            return false;
+        }
+        let earlier: usize = position
+            .iter()
+            .filter(|(_, first)| !first)
+            .map(|(split, _)| split.solutions.load(Ordering::Relaxed))
+            .sum();
+        earlier + found >= self.max_solutions
+    }
+    /// Counts solution found at `position` for branches later in search order
+    fn record(&self, position: &Position) {
+        for (split, first) in position {
+            if *first {
+                split.solutions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    /// Result of sequential search, which visits nodes in the same order as merged `outcome`.
+    /// Branches can run past the limits before earlier branches finish, so limits are checked again here.
+    fn result(&self, outcome: Outcome) -> Result<Option<Vec<Solution>>, SolverError> {
+        let max_nodes = self.options.max_nodes.unwrap_or(u64::MAX);
+        let mut solutions = vec![];
+        for (index, solution) in outcome.solutions {
+            if index > max_nodes {
+                return Err(SolverError::NodeLimitExceeded { max_nodes });
+            }
+            solutions.push(solution);
+            if solutions.len() == self.max_solutions {
+                return Ok(Some(solutions));
+            }
+        }
+        if outcome.nodes > max_nodes {
+            return Err(SolverError::NodeLimitExceeded { max_nodes });
+        }
+        if let Some(error) = outcome.error {
+            return Err(error);
+        }
+        Ok((!solutions.is_empty()).then_some(solutions))
+    }
+}
+/// Split of search tree between two branches, the first one is earlier in search order
+#[derive(Default)]
+struct Split {
+    /// Solutions found in the first branch
+    solutions: AtomicUsize,
+    /// Nodes visited in the first branch, counted only with `SolverOptions::max_nodes`
+    nodes: AtomicU64,
+    /// First branch hit a limit, so the second one is pointless
+    failed: AtomicBool,
+}
+/// Place of branch in parallel search: every split above it and whether the branch lies in the first branch
+/// of the split
+type Position<'a> = [(&'a Split, bool)];
+/// Part of search done by one branch
+#[derive(Default)]
+struct Outcome {
+    /// Solutions with number of nodes the branch visited up to each of them
+    solutions: Vec<(u64, Solution)>,
+    /// Nodes visited by the branch
+    nodes: u64,
+    /// Branch stopped before searching all of it, so branches later in search order don't matter
+    stopped: bool,
+    /// Limit hit at the last visited node
+    error: Option<SolverError>,
+}
+impl Outcome {
+    /// Joins outcome of branch which follows in search order
+    fn then(mut self, later: Outcome) -> Outcome {
+        if self.stopped {
+            return self;
+        }
+        let offset = self.nodes;
+        self.solutions.extend(
+            later
+                .solutions
+                .into_iter()
+                .map(|(index, solution)| (offset + index, solution)),
+        );
+        self.nodes += later.nodes;
+        self.stopped = later.stopped;
+        self.error = later.error;
+        self
+    }
+}
+/// Board with log of changes, so they can be undone when backtracking
+struct Trail {
+    board: Area,
+    log: Vec<(usize, Cell)>,
+}
+impl Trail {
+    fn new(board: Area) -> Self {
+        Self {
+            board,
+            log: Vec::new(),
+        }
+    }
+    fn mark(&self) -> usize {
+        self.log.len()
+    }
+    fn set(&mut self, index: usize, cell: Cell) {
+        let old = std::mem::replace(&mut self.board[index], cell);
+        self.log.push((index, old));
+    }
+    /// Keeps only possibilities satisfying `f`
+    fn retain<F: Fn(&u8) -> bool>(&mut self, index: usize, f: F) {
+        if let Cell::Possible(v) = &self.board[index] {
+            if !v.iter().all(&f) {
+                let v = v.iter().copied().filter(|x| f(x)).collect();
+                self.set(index, Cell::Possible(v));
+            }
+        }
+    }
+    /// Reverts every change made after `mark`
+    fn undo(&mut self, mark: usize) {
+        while self.log.len() > mark {
+            let (index, cell) = self.log.pop().unwrap();
+            self.board[index] = cell;
+        }
+    }
+}
+/// State of sequential search, so it can be paused after each solution
+struct Backtracker {
+    trail: Trail,
+    stack: Vec<Guess>,
+    depth: usize,
+    /// Current board is solved or impossible, so search continues from the last guess
+    backtrack: bool,
+    /// Solutions returned so far
+    found: usize,
+    /// Nodes visited so far
+    nodes: u64,
+}
+impl Backtracker {
+    fn new(board: Area, depth: usize) -> Self {
+        Self {
+            trail: Trail::new(board),
+            stack: Vec::new(),
+            depth,
+            backtrack: false,
+            found: 0,
+            nodes: 0,
+        }
+    }
+}
+/// Lazy iterator over solutions, created by `KenkenPuzzle::solutions`.
+/// Search runs only as far as needed for the next solution.
+pub struct Solutions<'a> {
+    puzzle: &'a KenkenPuzzle,
+    backtracker: Backtracker,
+    search: SearchState,
+    error: Option<SolverError>,
+}
+impl Solutions<'_> {
+    /// Limit which stopped iteration or `SolverError::InvalidPuzzle`, `None` if search finished or is still running
+    pub fn error(&self) -> Option<SolverError> {
+        self.error
+    }
+}
+impl Iterator for Solutions<'_> {
+    type Item = Solution;
+    fn next(&mut self) -> Option<Solution> {
+        if self.error.is_some() {
+            return None;
+        }
+        match self
+            .puzzle
+            .next_solution(&mut self.backtracker, &self.search, &[])
+        {
+            Ok(solution) => solution,
+            Err(error) => {
+                self.error = Some(error);
+                None
+            }
+        }
+    }
+}
+/// Witness that puzzle has more than one solution
+#[derive(Clone, Debug)]
+pub struct Ambiguity {
+    pub first: Solution,
+    pub second: Solution,
+    /// Indexes of cells where `first` and `second` differ
+    pub cells: Vec<usize>,
+    /// Indexes into `KenkenPuzzle::cages` of cages containing any of `cells`
+    pub cages: Vec<usize>,
+}
+/// Single rule of a puzzle, `KenkenPuzzle::diagnose` returns set of them which can't be satisfied together
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Constraint {
+    /// Index into `KenkenPuzzle::cages`
+    Cage(usize),
+    /// Row must contain every digit once
+    Row(usize),
+    /// Column must contain every digit once
+    Column(usize),
+    /// Box must contain every digit once, numbered by rows of boxes, see `Rules::box_shape`
+    Box(usize),
+    /// Diagonal must contain distinct digits, 0 is from top left corner and 1 from top right
+    Diagonal(usize),
+    /// Index into `KenkenPuzzle::regions`
+    Region(usize),
+    /// Cell index with given digit, see `KenkenPuzzle::givens`
+    Given(usize),
+    /// Index into `KenkenPuzzle::inequalities`
+    Inequality(usize),
+}
+/// Single clue change making the puzzle uniquely solvable, returned by `KenkenPuzzle::suggest_repairs`
+#[derive(Clone, Debug)]
+pub struct Repair {
+    /// Index into `KenkenPuzzle::cages`
+    pub cage: usize,
+    pub target: u32,
+    pub operation: MathOp,
+    /// The only solution of repaired puzzle
+    pub solution: Solution,
+}
+/// Guess on search stack
+struct Guess {
+    /// Trail length before guess was made
+    mark: usize,
+    cell: usize,
+    value: u8,
+    depth: usize,
+}
+/// Guess levels explored in parallel, deeper branches run sequentially on their thread
+#[cfg(feature = "parallel")]
+const PARALLEL_DEPTH: usize = 12;
+#[cfg(feature = "parallel")]
+fn parallel_split(level: usize) -> bool {
+    level < PARALLEL_DEPTH
+}
+#[cfg(not(feature = "parallel"))]
+fn parallel_split(_level: usize) -> bool {
+    false
+}
+/// Runs both branches of a guess in parallel
+#[cfg(feature = "parallel")]
+fn branch<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    rayon::join(a, b)
+}
+#[cfg(not(feature = "parallel"))]
+fn branch<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA,
+    B: FnOnce() -> RB,
+{
+    (a(), b())
+}
+/// Defines solver errors, each but `InvalidPuzzle` says which limit of `SolverOptions` was hit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum SolverError {
-    /// Exceeded `maximum_depth`
-    DepthExceeded,
+    /// Puzzle failed `KenkenPuzzle::validate`, so it wasn't searched
+    InvalidPuzzle(PuzzleError),
+    /// Exceeded `max_depth`
+    DepthExceeded { max_depth: usize },
+    /// Visited more than `max_nodes`
+    NodeLimitExceeded { max_nodes: u64 },
+    /// Ran longer than `time_limit`
+    TimeLimitExceeded { time_limit: Duration },
+    /// `cancel` token was cancelled
+    Cancelled,
+}
+/// Mistakes in puzzle found by `KenkenPuzzle::validate` or `KenkenPuzzle::parse`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PuzzleError {
+    /// Text isn't in the format of `KenkenPuzzle::format`
+    InvalidFormat,
+    /// Cell index is outside of grid
+    CellOutOfGrid { cell: usize },
+    /// Cell belongs to more than one cage
+    OverlappingCages { cell: usize },
+    /// Operation of cage can't be used with its number of cells
+    InvalidCageSize { cage: usize },
+    /// Given digit isn't one of `Rules::digits`
+    InvalidGiven { cell: usize },
+    /// Grid can't be split as `rules` require, e.g. into boxes
+    UnsupportedRules,
+    /// Cells of inequality aren't orthogonally adjacent
+    InvalidInequality { inequality: usize },
+    /// Regions don't split grid into `size` parts of `size` cells
+    InvalidRegions,
+    /// Largest digit `min_digit + size - 1` doesn't fit in `u8`, see `Rules::min_digit`
+    DigitsOutOfRange,
 }
 /// Structure defining puzzle in KenKen
 /// Used to find solutions for puzzle
@@ -170,13 +635,32 @@ pub enum SolverError {
 pub struct KenkenPuzzle {
     pub size: u8,
     pub cages: Vec<Cage>,
+    /// Digits filled in before solving, indexed by cell. Cell with given digit can be in a cage too.
+    pub givens: BTreeMap<usize, u8>,
+    /// Relations between neighboring cells which solution must satisfy besides cages
+    pub inequalities: Vec<Inequality>,
+    /// Cells of irregular (jigsaw) regions, each must contain every digit once. Empty in classic KenKen.
+    pub regions: Vec<Vec<usize>>,
+    pub rules: Rules,
 }
 impl KenkenPuzzle {
     pub fn new(size: u8, cages: Vec<Cage>) -> Self {
-        Self { size, cages }
+        Self {
+            size,
+            cages,
+            givens: BTreeMap::new(),
+            inequalities: Vec::new(),
+            regions: Vec::new(),
+            rules: Rules::default(),
+        }
     }
     pub fn format(&self) -> String {
-        let mut string = format!("{}<", self.size);
+        let mut string = self.size.to_string();
+        let rules = self.rules_tokens();
+        if !rules.is_empty() {
+            string += &format!(":{}", rules.join(","));
+        }
+        string += "<";
         for cage in &self.cages {
             let mut cell_str = String::from("");
             for id in &cage.cells {
@@ -192,175 +676,882 @@ impl KenkenPuzzle {
                     MathOp::Div => "d",
                     MathOp::Sub => "s",
                     MathOp::Free => "f",
+                    MathOp::Mod => "mod",
+                    MathOp::Pow => "pow",
+                    MathOp::Min => "min",
+                    MathOp::Max => "max",
+                    MathOp::Gcd => "gcd",
+                    MathOp::Lcm => "lcm",
+                    MathOp::Concat => "cat",
+                    MathOp::Unknown => "u",
                 },
                 cell_str
             );
         }
+        for (cell, digit) in &self.givens {
+            string += &format!("{}.g.{}>", digit, cell);
+        }
+        for inequality in &self.inequalities {
+            string += &format!("{}.gt.{}>", inequality.greater, inequality.less);
+        }
+        for (index, region) in self.regions.iter().enumerate() {
+            let cells: Vec<String> = region.iter().map(|cell| cell.to_string()).collect();
+            string += &format!("{}.r.{}>", index, cells.join(","));
+        }
         string
     }
+    /// Names of rule variants written by `format` after puzzle size
+    fn rules_tokens(&self) -> Vec<String> {
+        let mut tokens = vec![];
+        if self.rules.generalized_sub_div {
+            tokens.push(String::from("generalized"));
+        }
+        if self.rules.boxes {
+            tokens.push(String::from("boxes"));
+        }
+        if self.rules.diagonals {
+            tokens.push(String::from("diagonals"));
+        }
+        if self.rules.distinct_cages {
+            tokens.push(String::from("distinct"));
+        }
+        if self.rules.min_digit != Rules::default().min_digit {
+            tokens.push(format!("min={}", self.rules.min_digit));
+        }
+        tokens
+    }
+    /// Reads puzzle written by `format`. Only syntax is checked, use `validate` to check the puzzle itself.
+    pub fn parse(text: &str) -> Result<Self, PuzzleError> {
+        let (header, rest) = text
+            .trim()
+            .split_once('<')
+            .ok_or(PuzzleError::InvalidFormat)?;
+        let (size, rules) = header.split_once(':').unwrap_or((header, ""));
+        let size = size.parse().map_err(|_| PuzzleError::InvalidFormat)?;
+        let mut puzzle = Self::new(size, vec![]);
+        for token in rules.split(',').filter(|token| !token.is_empty()) {
+            match token {
+                "generalized" => puzzle.rules.generalized_sub_div = true,
+                "boxes" => puzzle.rules.boxes = true,
+                "diagonals" => puzzle.rules.diagonals = true,
+                "distinct" => puzzle.rules.distinct_cages = true,
+                _ if token.starts_with("min=") => {
+                    puzzle.rules.min_digit =
+                        token[4..].parse().map_err(|_| PuzzleError::InvalidFormat)?
+                }
+                _ => return Err(PuzzleError::InvalidFormat),
+            }
+        }
+        for entry in rest.split('>').filter(|entry| !entry.is_empty()) {
+            let mut parts = entry.split('.');
+            let (Some(target), Some(code), Some(cells), None) =
+                (parts.next(), parts.next(), parts.next(), parts.next())
+            else {
+                return Err(PuzzleError::InvalidFormat);
+            };
+            let target: u32 = target.parse().map_err(|_| PuzzleError::InvalidFormat)?;
+            let cells = cells
+                .split(',')
+                .map(|cell| cell.parse::<usize>())
+                .collect::<Result<Vec<usize>, _>>()
+                .map_err(|_| PuzzleError::InvalidFormat)?;
+            let operation = match code {
+                "a" => MathOp::Add,
+                "m" => MathOp::Mul,
+                "d" => MathOp::Div,
+                "s" => MathOp::Sub,
+                "f" => MathOp::Free,
+                "mod" => MathOp::Mod,
+                "pow" => MathOp::Pow,
+                "min" => MathOp::Min,
+                "max" => MathOp::Max,
+                "gcd" => MathOp::Gcd,
+                "lcm" => MathOp::Lcm,
+                "cat" => MathOp::Concat,
+                "u" => MathOp::Unknown,
+                "g" => {
+                    let (&[cell], Ok(digit)) = (&cells[..], u8::try_from(target)) else {
+                        return Err(PuzzleError::InvalidFormat);
+                    };
+                    puzzle.givens.insert(cell, digit);
+                    continue;
+                }
+                "r" if target as usize == puzzle.regions.len() => {
+                    puzzle.regions.push(cells);
+                    continue;
+                }
+                "gt" => {
+                    let &[less] = &cells[..] else {
+                        return Err(PuzzleError::InvalidFormat);
+                    };
+                    let greater = target as usize;
+                    puzzle.inequalities.push(Inequality { greater, less });
+                    continue;
+                }
+                _ => return Err(PuzzleError::InvalidFormat),
+            };
+            puzzle.cages.push(Cage {
+                target,
+                operation,
+                cells,
+            });
+        }
+        Ok(puzzle)
+    }
+    /// Checks that cages and givens fit the grid, without solving the puzzle
+    pub fn validate(&self) -> Result<(), PuzzleError> {
+        if self.rules.min_digit as usize + self.size as usize > u8::MAX as usize + 1 {
+            return Err(PuzzleError::DigitsOutOfRange);
+        }
+        if self.rules.boxes && self.rules.box_shape(self.size).is_none() {
+            return Err(PuzzleError::UnsupportedRules);
+        }
+        if self.rules.diagonals && (self.size == 2 || self.size == 3) {
+            return Err(PuzzleError::UnsupportedRules);
+        }
+        let cell_count = self.size as usize * self.size as usize;
+        let mut covered = vec![false; cell_count];
+        for (index, cage) in self.cages.iter().enumerate() {
+            for cell in &cage.cells {
+                match covered.get_mut(*cell) {
+                    None => return Err(PuzzleError::CellOutOfGrid { cell: *cell }),
+                    Some(true) => return Err(PuzzleError::OverlappingCages { cell: *cell }),
+                    Some(covered) => *covered = true,
+                }
+            }
+            if !cage.operation.fits(cage.cells.len(), &self.rules) {
+                return Err(PuzzleError::InvalidCageSize { cage: index });
+            }
+        }
+        for (cell, digit) in &self.givens {
+            if *cell >= cell_count {
+                return Err(PuzzleError::CellOutOfGrid { cell: *cell });
+            }
+            if !self.rules.digits(self.size).contains(digit) {
+                return Err(PuzzleError::InvalidGiven { cell: *cell });
+            }
+        }
+        let size = self.size as usize;
+        for (index, inequality) in self.inequalities.iter().enumerate() {
+            let (a, b) = (inequality.greater, inequality.less);
+            if let Some(cell) = [a, b].into_iter().find(|cell| *cell >= cell_count) {
+                return Err(PuzzleError::CellOutOfGrid { cell });
+            }
+            if !((a / size == b / size && a.abs_diff(b) == 1) || a.abs_diff(b) == size) {
+                return Err(PuzzleError::InvalidInequality { inequality: index });
+            }
+        }
+        if !self.regions.is_empty() {
+            let mut covered = vec![false; cell_count];
+            if self.regions.len() != size || self.regions.iter().any(|region| region.len() != size)
+            {
+                return Err(PuzzleError::InvalidRegions);
+            }
+            for cell in self.regions.iter().flatten() {
+                match covered.get_mut(*cell) {
+                    None => return Err(PuzzleError::CellOutOfGrid { cell: *cell }),
+                    Some(true) => return Err(PuzzleError::InvalidRegions),
+                    Some(covered) => *covered = true,
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Draws puzzle as text, with cage borders, cage targets in the first cell of each cage and given digits.
+    /// Puzzle has to pass `validate`.
+    pub fn render(&self) -> Result<String, PuzzleError> {
+        self.validate()?;
+        let size = self.size as usize;
+        let mut cage_of = vec![None; size * size];
+        let mut clues = vec![String::new(); size * size];
+        for (index, cage) in self.cages.iter().enumerate() {
+            for cell in &cage.cells {
+                cage_of[*cell] = Some(index);
+            }
+            let symbol = match cage.operation {
+                MathOp::Add => "+",
+                MathOp::Sub => "-",
+                MathOp::Mul => "*",
+                MathOp::Div => "/",
+                MathOp::Mod => "%",
+                MathOp::Pow => "^",
+                MathOp::Min => "min",
+                MathOp::Max => "max",
+                MathOp::Gcd => "gcd",
+                MathOp::Lcm => "lcm",
+                MathOp::Concat => "||",
+                MathOp::Free | MathOp::Unknown => "",
+            };
+            if let Some(first) = cage.cells.iter().min() {
+                clues[*first] = format!("{}{}", cage.target, symbol);
+            }
+        }
+        let width = clues
+            .iter()
+            .map(|clue| clue.len())
+            .max()
+            .unwrap_or(0)
+            .max(3);
+        //Cells without cage are separated from everything
+        let same_cage = |a: usize, b: usize| cage_of[a].is_some() && cage_of[a] == cage_of[b];
+        //Symbol opens towards the larger digit
+        let relation = |a: usize, b: usize, symbols: [&'static str; 2]| {
+            self.inequalities.iter().find_map(|inequality| {
+                match (inequality.greater, inequality.less) {
+                    (g, l) if (g, l) == (a, b) => Some(symbols[0]),
+                    (g, l) if (g, l) == (b, a) => Some(symbols[1]),
+                    _ => None,
+                }
+            })
+        };
+        let mut text = String::new();
+        for row in 0..=size {
+            for col in 0..size {
+                let cell = row * size + col;
+                let border = row == 0 || row == size || !same_cage(cell - size, cell);
+                let mut segment = (if border { "-" } else { " " }).repeat(width);
+                if row > 0 && row < size {
+                    if let Some(symbol) = relation(cell - size, cell, ["v", "^"]) {
+                        segment.replace_range(width / 2..width / 2 + 1, symbol);
+                    }
+                }
+                text += "+";
+                text += &segment;
+            }
+            text += "+\n";
+            if row == size {
+                break;
+            }
+            for line in 0..2 {
+                for col in 0..size {
+                    let cell = row * size + col;
+                    let border = col == 0 || !same_cage(cell - 1, cell);
+                    text += match (line, col) {
+                        (1, 1..) => relation(cell - 1, cell, [">", "<"]),
+                        _ => None,
+                    }
+                    .unwrap_or(if border { "|" } else { " " });
+                    let content = match (line, self.givens.get(&cell)) {
+                        (0, _) => clues[cell].clone(),
+                        (_, Some(digit)) => digit.to_string(),
+                        _ => String::new(),
+                    };
+                    text += &format!("{:^1$}", content, width);
+                }
+                text += "|\n";
+            }
+        }
+        Ok(text)
+    }
     /// Finds and returns solutions to puzzle.
     /// Time to solve rapidly grows with puzzle size.
     /// # Arguments
-    /// * `max_depth` - Limits `depth` when solving puzzle. If exceeded search will be aborted and return `None`. (`depth` counts recursion cycles which occurs when guessing solutions is needed)
+    /// * `max_depth` - Limits `depth` when solving puzzle. If exceeded search will be aborted and return `SolverError`. (`depth` counts nested guesses which occurs when guessing solutions is needed)
     /// * 'max_solutions' - Stops search early if found enough solutions. When set to `0` solutions are unlimited.
+    ///
+    /// With `parallel` feature the top levels of guessing are explored on all cores.
+    /// Puzzle which fails `validate` isn't searched and gives `SolverError::InvalidPuzzle`.
     pub fn solve(
         &self,
         max_depth: &usize,
         max_solutions: &usize,
+    ) -> Result<Option<Vec<Solution>>, SolverError> {
+        self.solve_with_options(&SolverOptions {
+            max_depth: Some(*max_depth),
+            max_solutions: (*max_solutions != 0).then_some(*max_solutions),
+            ..Default::default()
+        })
+    }
+    /// Same as `solve`, with every limit set independently in `options`.
+    /// With `parallel` feature limits other than `time_limit` and `cancel` trip at the same node as without it.
+    pub fn solve_with_options(
+        &self,
+        options: &SolverOptions,
     ) -> Result<Option<Vec<Solution>>, SolverError> {
         //Returns all found solutions
-        return self.find_solutions(self.get_area(), 0, max_depth, max_solutions);
+        self.validate().map_err(SolverError::InvalidPuzzle)?;
+        let search = SearchState::new(options);
+        search.result(self.find_solutions(self.get_area(), 0, 0, &search, &[]))
     }
-    fn get_area(&self) -> Area {
-        let possible = Vec::from_iter(1..self.size + 1);
-        vec![Cell::Possible(possible); self.size as usize * self.size as usize]
+    /// Looks for two different solutions and returns where they differ.
+    /// Returns `None` if puzzle has one or no solution.
+    /// `options.max_solutions` is ignored.
+    pub fn ambiguity(&self, options: &SolverOptions) -> Result<Option<Ambiguity>, SolverError> {
+        let options = SolverOptions {
+            max_solutions: Some(2),
+            ..options.clone()
+        };
+        let mut solutions = self.solutions_with_options(&options);
+        let (first, second) = (solutions.next(), solutions.next());
+        if let Some(error) = solutions.error() {
+            return Err(error);
+        }
+        match (first, second) {
+            (Some(first), Some(second)) => Ok(Some(self.compare_solutions(first, second))),
+            _ => Ok(None),
+        }
     }
-    /// Main solver recursive function
-    fn find_solutions(
+    /// Builds `Ambiguity` from two solutions of this puzzle
+    pub fn compare_solutions(&self, first: Solution, second: Solution) -> Ambiguity {
+        let mut cells = vec![];
+        for (i, (a, b)) in first.grid.0.iter().zip(second.grid.0.iter()).enumerate() {
+            if a != b {
+                cells.push(i);
+            }
+        }
+        let mut cages = vec![];
+        for (i, cage) in self.cages.iter().enumerate() {
+            if cage.cells.iter().any(|cell| cells.contains(cell)) {
+                cages.push(i);
+            }
+        }
+        Ambiguity {
+            first,
+            second,
+            cells,
+            cages,
+        }
+    }
+    /// Every cage, row, column and given digit of puzzle
+    pub fn constraints(&self) -> Vec<Constraint> {
+        let mut constraints: Vec<Constraint> =
+            (0..self.cages.len()).map(Constraint::Cage).collect();
+        constraints.extend(self.units().into_iter().map(|(unit, _)| unit));
+        constraints.extend(self.givens.keys().copied().map(Constraint::Given));
+        constraints.extend((0..self.inequalities.len()).map(Constraint::Inequality));
+        constraints
+    }
+    /// Explains why puzzle has no solution.
+    /// Returns minimal set of constraints which can't be satisfied together, removing any one of them makes the rest solvable.
+    /// Returns `None` if puzzle has a solution.
+    ///
+    /// Every subset is checked by separate search, `options` limits each of them and `options.max_solutions` is ignored.
+    pub fn diagnose(
         &self,
-        mut board: Area,
-        depth: usize,
-        max_depth: &usize,
-        max_solutions: &usize,
-    ) -> Result<Option<Vec<Solution>>, SolverError> {
-        if depth > *max_depth {
-            return Err(SolverError::DepthExceeded); //too deep, stop search
+        options: &SolverOptions,
+    ) -> Result<Option<Vec<Constraint>>, SolverError> {
+        if self.satisfiable(&[], options)? {
+            return Ok(None);
         }
-        let mut progress = true;
-        while progress {
-            //Loop will continue as long it can solve cells with just deduction
-            progress = false;
-            self.deduction(board.as_mut());
-            for cell in board.iter_mut() {
-                match cell {
-                    Cell::Possible(v) => {
-                        if v.len() == 0 {
-                            //Solution impossible in this state
-                            return Ok(None);
-                        } else if v.len() == 1 {
-                            //Cell solved
-                            *cell = Cell::Solution(v[0]);
-                            progress = true;
+        let mut order = self.constraints();
+        //Rows and columns are dropped first, then units of rule variants, givens and cages last,
+        //so conflict is explained by clues and variant rules where possible
+        order.sort_by_key(|constraint| match constraint {
+            Constraint::Cage(_) => 0,
+            Constraint::Given(_) | Constraint::Inequality(_) => 1,
+            Constraint::Box(_) | Constraint::Diagonal(_) | Constraint::Region(_) => 2,
+            Constraint::Row(_) | Constraint::Column(_) => 3,
+        });
+        let mut core = vec![];
+        let mut ignored = vec![];
+        for constraint in order.into_iter().rev() {
+            //Constraint is needed for conflict if puzzle without it is solvable
+            ignored.push(constraint);
+            if self.satisfiable(&ignored, options)? {
+                ignored.pop();
+                core.push(constraint);
+            }
+        }
+        core.sort();
+        Ok(Some(core))
+    }
+    /// Looks for changes of one cage target or operation which make unsolvable or ambiguous puzzle uniquely solvable.
+    /// Returned repairs are ordered from the most likely typo, changed operation and digits of target count as one edit each.
+    /// Returns no repairs if puzzle already has unique solution.
+    ///
+    /// Only cages in the conflict found by `diagnose` or in the `Ambiguity` are changed.
+    /// All solutions of puzzle without changed cage are searched, `options` limits each of these searches.
+    pub fn suggest_repairs(&self, options: &SolverOptions) -> Result<Vec<Repair>, SolverError> {
+        let cages = match self.ambiguity(options)? {
+            Some(ambiguity) => ambiguity.cages,
+            None => match self.diagnose(options)? {
+                Some(core) => core
+                    .into_iter()
+                    .filter_map(|constraint| match constraint {
+                        Constraint::Cage(cage) => Some(cage),
+                        _ => None,
+                    })
+                    .collect(),
+                None => return Ok(vec![]),
+            },
+        };
+        //Unusual operations are suggested only in puzzles which already use them
+        let operations: Vec<MathOp> = MathOp::KNOWN
+            .into_iter()
+            .filter(|op| {
+                MathOp::CLASSIC.contains(op) || self.cages.iter().any(|cage| cage.operation == *op)
+            })
+            .collect();
+        let mut repairs = vec![];
+        for index in cages {
+            let cage = &self.cages[index];
+            let mut search = SearchState::new(&SolverOptions {
+                max_solutions: None,
+                ..options.clone()
+            });
+            search.ignored = vec![Constraint::Cage(index)];
+            let mut backtracker = Backtracker::new(self.get_area(), 0);
+            //Every clue which the cage could show, with number of solutions and the first one
+            let mut clues: Vec<(MathOp, u32, usize, Solution)> = vec![];
+            while let Some(solution) = self.next_solution(&mut backtracker, &search, &[])? {
+                let values: Vec<u8> = cage.cells.iter().map(|i| solution.grid.0[*i]).collect();
+                let mut shown: Vec<(MathOp, u32)> = vec![];
+                for operation in operations.iter().copied() {
+                    if !operation.fits(values.len(), &self.rules) {
+                        continue;
+                    }
+                    if let Some(target) = operation.evaluate(&values) {
+                        shown.push((operation, target));
+                    }
+                }
+                //Hidden operation keeps showing only the target, it stands for the same operations as in solver
+                if cage.operation == MathOp::Unknown {
+                    for operation in MathOp::CLASSIC {
+                        if !operation.fits(values.len(), &self.rules) {
+                            continue;
+                        }
+                        if let Some(target) = operation.evaluate(&values) {
+                            if !shown.contains(&(MathOp::Unknown, target)) {
+                                shown.push((MathOp::Unknown, target));
+                            }
                         }
                     }
-                    _ => (),
+                }
+                for (operation, target) in shown {
+                    match clues.iter_mut().find(|c| c.0 == operation && c.1 == target) {
+                        Some(clue) => clue.2 += 1,
+                        None => clues.push((operation, target, 1, solution.clone())),
+                    }
+                }
+            }
+            for (operation, target, count, solution) in clues {
+                if count == 1 && (operation != cage.operation || target != cage.target) {
+                    repairs.push(Repair {
+                        cage: index,
+                        target,
+                        operation,
+                        solution,
+                    });
                 }
             }
         }
+        repairs.sort_by_key(|repair| {
+            let cage = &self.cages[repair.cage];
+            let edits = (repair.operation != cage.operation) as usize
+                + typo_distance(repair.target, cage.target);
+            (edits, repair.target.abs_diff(cage.target), repair.cage)
+        });
+        Ok(repairs)
+    }
+    fn satisfiable(
+        &self,
+        ignored: &[Constraint],
+        options: &SolverOptions,
+    ) -> Result<bool, SolverError> {
+        self.validate().map_err(SolverError::InvalidPuzzle)?;
+        let mut search = SearchState::new(&SolverOptions {
+            max_solutions: Some(1),
+            ..options.clone()
+        });
+        search.ignored = ignored.to_vec();
+        let outcome = self.find_solutions(self.get_area(), 0, 0, &search, &[]);
+        Ok(search.result(outcome)?.is_some())
+    }
+    /// Returns iterator yielding solutions one by one, in the same order as `solve`.
+    /// Search is unlimited, so it can take very long for puzzles with few cages.
+    pub fn solutions(&self) -> Solutions<'_> {
+        self.solutions_with_options(&SolverOptions::default())
+    }
+    /// Same as `solutions`, but stops when any of `options` limits is reached, see `Solutions::error`
+    pub fn solutions_with_options(&self, options: &SolverOptions) -> Solutions<'_> {
+        Solutions {
+            puzzle: self,
+            backtracker: Backtracker::new(self.get_area(), 0),
+            search: SearchState::new(options),
+            error: self.validate().err().map(SolverError::InvalidPuzzle),
+        }
+    }
+    fn get_area(&self) -> Area {
+        let possible = Vec::from_iter(self.rules.digits(self.size));
+        vec![Cell::Possible(possible); self.size as usize * self.size as usize]
+    }
+    /// Solver entry, splits top `level`s of search tree between threads and solves the rest with `search`.
+    /// `SearchState::result` turns the outcome into solutions.
+    fn find_solutions(
+        &self,
+        board: Area,
+        depth: usize,
+        level: usize,
+        search: &SearchState,
+        position: &Position,
+    ) -> Outcome {
+        if !parallel_split(level) {
+            return self.search(board, depth, search, position);
+        }
+        if search.is_done(position, 0) {
+            //Earlier branch found enough solutions or failed
+            return Outcome {
+                stopped: true,
+                ..Default::default()
+            };
+        }
+        let mut outcome = Outcome {
+            nodes: 1,
+            ..Default::default()
+        };
+        if let Err(error) = search.visit(depth, position, 1) {
+            outcome.stopped = true;
+            outcome.error = Some(error);
+            return outcome;
+        }
+        let mut trail = Trail::new(board);
+        if !self.propagate(&mut trail, &search.ignored) {
+            return outcome;
+        }
+        let mut board = trail.board;
         //Candidate with least possibilities
         if let Option::Some(candidate) = board.get_best_candidate() {
             //guess
             let mut guess = board.clone();
             if let Cell::Possible(v) = &mut board[candidate.1] {
-                let mut solutions = vec![];
-                //To make a guess put possibility as solution into board clone and try to solve that
-                let num = v[0];
+                //To make a guess put possibility as solution into board clone and try to solve that,
+                //the rest of board is tried without that possibility
+                let num = v.remove(0);
                 guess[candidate.1] = Cell::Solution(num);
-                match unwrap_or_return!(self.find_solutions(
-                    guess,
-                    depth + 1,
-                    max_depth,
-                    max_solutions
-                )) {
-                    Some(mut sol1) => {
-                        //Guess was correct
-                        solutions.append(&mut sol1);
-                        if solutions.len() == *max_solutions {
-                            //found enough solutions
-                            return Ok(Some(solutions));
-                        }
-                        v.remove(0);
-                        //try remaining guesses
-                        match unwrap_or_return!(self.find_solutions(
-                            board,
-                            depth + 1,
-                            max_depth,
-                            max_solutions
-                        )) {
-                            Some(mut sol2) => {
-                                //Don't pass analysis, because first solution was already found
-                                solutions.append(&mut sol2);
-                                return Ok(Some(solutions));
-                            }
-                            None => return Ok(Some(solutions)),
-                        }
-                    }
-                    None => {
-                        //Ruled out a possibility, so rerun this function with currect board
-                        v.remove(0);
-                        return self.find_solutions(board, depth + 1, max_depth, max_solutions);
-                    }
-                }
+                let split = Split::default();
+                let guess_position = [position, &[(&split, true)]].concat();
+                let rest_position = [position, &[(&split, false)]].concat();
+                let (sol1, sol2) = branch(
+                    || self.find_solutions(guess, depth + 1, level + 1, search, &guess_position),
+                    || self.find_solutions(board, depth, level + 1, search, &rest_position),
+                );
+                //Second branch is dropped if the first one stopped, even if it found solutions or failed
+                outcome.then(sol1).then(sol2)
             } else {
                 //unreachable
                 panic!("Cosmic ray detected.");
             }
         } else {
-            println!("sol");
-            return Ok(Some(vec![Solution::from_area(&board, self.size, depth)]));
+            search.record(position);
+            outcome
+                .solutions
+                .push((1, Solution::from_area(&board, self.size, depth)));
+            outcome.stopped = search.is_done(position, 1);
+            outcome
             //no more candidates => all solved
         }
     }
-    fn deduction(&self, board: &mut Area) {
-        //find possibilities within cages
-        for cage in self.cages.iter() {
-            let mut area = Vec::<Cell>::with_capacity(cage.cells.len());
-            for i in cage.cells.iter() {
-                area.push(board[*i].clone());
-            }
-            cage.solve(&mut area, self.size);
-            for (e, i) in cage.cells.iter().enumerate() {
-                board[*i].clone_from(&area[e]);
+    /// Collects solutions of `board` found by `next_solution`
+    fn search(
+        &self,
+        board: Area,
+        depth: usize,
+        search: &SearchState,
+        position: &Position,
+    ) -> Outcome {
+        let mut backtracker = Backtracker::new(board, depth);
+        let mut solutions = vec![];
+        let error = loop {
+            match self.next_solution(&mut backtracker, search, position) {
+                Ok(Some(solution)) => solutions.push((backtracker.nodes, solution)),
+                Ok(None) => break None,
+                Err(error) => break Some(error),
             }
+        };
+        Outcome {
+            solutions,
+            nodes: backtracker.nodes,
+            stopped: error.is_some() || search.is_done(position, backtracker.found),
+            error,
         }
-        //check rows
-        for i in (0..board.len()).step_by(self.size as usize) {
-            let row = &mut board[i..i + self.size as usize];
-            let mut filter_out = Vec::new();
-            for cell in row.iter() {
-                if let Cell::Solution(n) = cell {
-                    filter_out.push(*n);
+    }
+    /// Main solver function, depth-first search with explicit stack.
+    /// Every guess has two branches: candidate is set to its first possibility, or that possibility is removed.
+    /// Changes made in a branch are recorded in trail and undone when backtracking.
+    /// Returns next solution and can be called again to continue search after it.
+    fn next_solution(
+        &self,
+        backtracker: &mut Backtracker,
+        search: &SearchState,
+        position: &Position,
+    ) -> Result<Option<Solution>, SolverError> {
+        let Backtracker {
+            trail,
+            stack,
+            depth,
+            backtrack,
+            found,
+            nodes,
+        } = backtracker;
+        loop {
+            if *backtrack {
+                //backtrack to the last guess and try board without guessed possibility
+                match stack.pop() {
+                    Some(guess) => {
+                        trail.undo(guess.mark);
+                        trail.retain(guess.cell, |x| *x != guess.value);
+                        *depth = guess.depth;
+                        *backtrack = false;
+                    }
+                    None => return Ok(None),
                 }
             }
-            for cell in row.iter_mut() {
-                if let Cell::Possible(v) = cell {
-                    v.retain(|x| !filter_out.contains(x));
+            if search.is_done(position, *found) {
+                //Enough solutions were found or other branch failed
+                return Ok(None);
+            }
+            *nodes += 1;
+            unwrap_or_return!(search.visit(*depth, position, *nodes));
+            *backtrack = true;
+            if self.propagate(trail, &search.ignored) {
+                //Candidate with least possibilities
+                if let Some(candidate) = trail.board.get_best_candidate() {
+                    if let Cell::Possible(v) = &trail.board[candidate.1] {
+                        let guess = Guess {
+                            mark: trail.mark(),
+                            cell: candidate.1,
+                            value: v[0],
+                            depth: *depth,
+                        };
+                        trail.set(guess.cell, Cell::Solution(guess.value));
+                        stack.push(guess);
+                        *depth += 1;
+                        *backtrack = false;
+                    }
+                } else {
+                    //no more candidates => all solved
+                    *found += 1;
+                    search.record(position);
+                    return Ok(Some(Solution::from_area(&trail.board, self.size, *depth)));
                 }
             }
         }
-        //check columns
-        for col in 0..self.size as usize {
-            let mut contained = Vec::new();
-            for i in (col..board.len()).step_by(self.size as usize) {
-                let cell = &board[i];
-                if let Cell::Solution(n) = cell {
-                    contained.push(*n);
+    }
+    /// Runs deduction as long it can solve cells, returns `false` if board has no solution
+    fn propagate(&self, trail: &mut Trail, ignored: &[Constraint]) -> bool {
+        let mut progress = true;
+        while progress {
+            //Loop will continue as long it can solve cells with just deduction
+            progress = false;
+            if !self.deduction(trail, ignored) {
+                return false;
+            }
+            for i in 0..trail.board.len() {
+                if let Cell::Possible(v) = &trail.board[i] {
+                    if v.is_empty() {
+                        //Solution impossible in this state
+                        return false;
+                    } else if v.len() == 1 {
+                        //Cell solved
+                        trail.set(i, Cell::Solution(v[0]));
+                        progress = true;
+                    }
                 }
             }
-            for i in (col..board.len()).step_by(self.size as usize) {
-                let cell = &mut board[i];
-                if let Cell::Possible(v) = cell {
-                    v.retain(|x| !contained.contains(x));
+        }
+        true
+    }
+    /// For each cell of cage, earlier cells of the same cage sharing its row, column or other unit.
+    /// Units in `ignored` don't count. With `Rules::distinct_cages` every earlier cell.
+    fn cage_conflicts(&self, cage: &Cage, ignored: &[Constraint]) -> Vec<Vec<usize>> {
+        if self.rules.distinct_cages {
+            return (0..cage.cells.len()).map(|i| (0..i).collect()).collect();
+        }
+        cage.cells
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| {
+                (0..i)
+                    .filter(|j| self.shared_unit(cage.cells[*j], *cell, ignored))
+                    .collect()
+            })
+            .collect()
+    }
+    /// Whether cells `a` and `b` share a unit, so they can't hold the same digit
+    pub(crate) fn peers(&self, a: usize, b: usize) -> bool {
+        self.shared_unit(a, b, &[])
+    }
+    /// Whether cells `a` and `b` share a unit which isn't in `ignored`
+    fn shared_unit(&self, a: usize, b: usize, ignored: &[Constraint]) -> bool {
+        let size = self.size as usize;
+        let active = |unit: Constraint| !ignored.contains(&unit);
+        if a / size == b / size && active(Constraint::Row(a / size)) {
+            return true;
+        }
+        if a % size == b % size && active(Constraint::Column(a % size)) {
+            return true;
+        }
+        if let Some((rows, cols)) = self.rules.box_shape(self.size) {
+            //Numbered the same way as in `units`
+            let box_of = |cell: usize| cell / size / rows * (size / cols) + cell % size / cols;
+            if box_of(a) == box_of(b) && active(Constraint::Box(box_of(a))) {
+                return true;
+            }
+        }
+        if self.rules.diagonals {
+            let main = |cell: usize| cell / size == cell % size;
+            let anti = |cell: usize| cell / size + cell % size == size - 1;
+            if main(a) && main(b) && active(Constraint::Diagonal(0)) {
+                return true;
+            }
+            if anti(a) && anti(b) && active(Constraint::Diagonal(1)) {
+                return true;
+            }
+        }
+        self.regions.iter().enumerate().any(|(index, region)| {
+            region.contains(&a) && region.contains(&b) && active(Constraint::Region(index))
+        })
+    }
+    /// Rows, columns and other units of the rules, with their cells. Every unit contains each digit once.
+    fn units(&self) -> Vec<(Constraint, Vec<usize>)> {
+        let size = self.size as usize;
+        let mut units = Vec::new();
+        for row in 0..size {
+            units.push((
+                Constraint::Row(row),
+                (row * size..(row + 1) * size).collect(),
+            ));
+        }
+        for col in 0..size {
+            units.push((
+                Constraint::Column(col),
+                (col..size * size).step_by(size).collect(),
+            ));
+        }
+        if let Some((rows, cols)) = self.rules.box_shape(self.size) {
+            let stacks = size / cols;
+            for index in 0..size {
+                let (top, left) = (index / stacks * rows, index % stacks * cols);
+                let cells = (0..size).map(|i| (top + i / cols) * size + left + i % cols);
+                units.push((Constraint::Box(index), cells.collect()));
+            }
+        }
+        if self.rules.diagonals {
+            let main = (0..size).map(|i| i * size + i).collect();
+            let anti = (0..size).map(|i| i * size + size - 1 - i).collect();
+            units.push((Constraint::Diagonal(0), main));
+            units.push((Constraint::Diagonal(1), anti));
+        }
+        for (index, region) in self.regions.iter().enumerate() {
+            units.push((Constraint::Region(index), region.clone()));
+        }
+        units
+    }
+    /// Removes possibilities ruled out by cages, inequalities, rows, columns and other units, returns `false` if any of them is broken
+    fn deduction(&self, trail: &mut Trail, ignored: &[Constraint]) -> bool {
+        for (cell, digit) in &self.givens {
+            if ignored.contains(&Constraint::Given(*cell)) {
+                continue;
+            }
+            match &trail.board[*cell] {
+                Cell::Solution(n) if n != digit => return false,
+                Cell::Solution(_) => (),
+                Cell::Possible(v) if v.contains(digit) => trail.set(*cell, Cell::Solution(*digit)),
+                Cell::Possible(_) => return false,
+            }
+        }
+        for (index, inequality) in self.inequalities.iter().enumerate() {
+            if ignored.contains(&Constraint::Inequality(index)) {
+                continue;
+            }
+            let bounds = |cell: usize| match &trail.board[cell] {
+                Cell::Solution(n) => Some((*n, *n)),
+                Cell::Possible(v) => Some((*v.iter().min()?, *v.iter().max()?)),
+            };
+            let (Some(greater), Some(less)) = (bounds(inequality.greater), bounds(inequality.less))
+            else {
+                return false;
+            };
+            if greater.1 <= less.0 {
+                return false;
+            }
+            trail.retain(inequality.greater, |x| *x > less.0);
+            trail.retain(inequality.less, |x| *x < greater.1);
+        }
+        //find possibilities within cages
+        for (c, cage) in self.cages.iter().enumerate() {
+            if ignored.contains(&Constraint::Cage(c)) {
+                continue;
+            }
+            let mut area = Vec::<Cell>::with_capacity(cage.cells.len());
+            for i in cage.cells.iter() {
+                area.push(trail.board[*i].clone());
+            }
+            if !cage.solve(
+                &mut area,
+                self.size,
+                &self.cage_conflicts(cage, ignored),
+                &self.rules,
+            ) {
+                return false;
+            }
+            for (cell, i) in area.into_iter().zip(cage.cells.iter()) {
+                if trail.board[*i] != cell {
+                    trail.set(*i, cell);
                 }
             }
         }
+        //check rows, columns and other units
+        for (unit, cells) in self.units() {
+            if ignored.contains(&unit) {
+                continue;
+            }
+            if !Self::check_line(trail, &cells) {
+                return false;
+            }
+        }
+        true
+    }
+    /// Removes solved digits of line from its other cells, returns `false` if a digit is solved twice
+    fn check_line(trail: &mut Trail, cells: &[usize]) -> bool {
+        let mut contained = Vec::new();
+        for i in cells {
+            if let Cell::Solution(n) = &trail.board[*i] {
+                if contained.contains(n) {
+                    return false;
+                }
+                contained.push(*n);
+            }
+        }
+        for i in cells {
+            trail.retain(*i, |x| !contained.contains(x));
+        }
+        true
     }
 }
+/// Number of digits which have to be inserted, removed or replaced to write `b` instead of `a`
+fn typo_distance(a: u32, b: u32) -> usize {
+    let (a, b) = (a.to_string().into_bytes(), b.to_string().into_bytes());
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut diagonal = row[0];
+        row[0] = i;
+        for j in 1..=b.len() {
+            let replaced = diagonal + (a[i - 1] != b[j - 1]) as usize;
+            diagonal = row[j];
+            row[j] = replaced.min(row[j] + 1).min(row[j - 1] + 1);
+        }
+    }
+    row[b.len()]
+}
 // Generator functions to generate possible values in cage
 mod sequence_gen {
 
     use super::Cell;
+    use std::ops::RangeInclusive;
+    /// `distinct` forbids the same digit in both cells
     pub fn generate_sequences_sub(
-        size: u8,
+        digits: RangeInclusive<u8>,
         target: u32,
         area: Option<(&Cell, &Cell)>,
+        distinct: bool,
     ) -> Vec<(u8, u8)> {
         let t = target as u8;
         let mut pairs = Vec::new();
-        for n in 1..=size {
+        for n in digits.clone() {
             let d = n.checked_sub(t);
             if let Some(d) = d {
-                if d > 0 && d <= size && d != n {
+                if digits.contains(&d) && (d != n || !distinct) {
                     pairs.push((n, d));
                 }
             }
@@ -411,23 +1602,26 @@ mod sequence_gen {
             if pass.0 {
                 sequences.push(*p);
             }
-            if pass.1 {
+            if pass.1 && p.0 != p.1 {
                 sequences.push((p.1, p.0));
             }
         }
         sequences
     }
+    /// `distinct` forbids the same digit in both cells
     pub fn generate_sequences_div(
-        size: u8,
+        digits: RangeInclusive<u8>,
         target: u32,
         area: Option<(&Cell, &Cell)>,
+        distinct: bool,
     ) -> Vec<(u8, u8)> {
         //DIV must be only on two cells
         let t = target as u8;
         let mut pairs = Vec::new();
-        for i in 1..=size {
-            for j in 1..=size {
-                if i != j && i % j == 0 && i / j == t {
+        for i in digits.clone() {
+            for j in digits.clone() {
+                //Zero can be divided, but not a divisor
+                if j != 0 && (i != j || !distinct) && i % j == 0 && i / j == t {
                     pairs.push((i, j));
                 }
             }
@@ -478,28 +1672,39 @@ mod sequence_gen {
             if pass.0 {
                 sequences.push(*p);
             }
-            if pass.1 {
+            if pass.1 && p.0 != p.1 {
                 sequences.push((p.1, p.0));
             }
         }
         sequences
     }
+    /// `conflicts[i]` lists earlier positions which can't hold the same number as position `i`
     pub fn generate_sequences_mul(
         len: usize,
-        max: u8,
+        digits: RangeInclusive<u8>,
         target: u32,
         area: Option<&Vec<Cell>>,
+        conflicts: &[Vec<usize>],
     ) -> Vec<Vec<u8>> {
         let mut sequences = Vec::new();
         let mut sequence = Vec::new();
-        gen_seq_mul_recursive(len, max, target, area, &mut sequence, &mut sequences);
+        gen_seq_mul_recursive(
+            len,
+            digits,
+            target,
+            area,
+            conflicts,
+            &mut sequence,
+            &mut sequences,
+        );
         sequences
     }
     fn gen_seq_mul_recursive(
         len: usize,
-        max: u8,
+        digits: RangeInclusive<u8>,
         target: u32,
         area: Option<&Vec<Cell>>,
+        conflicts: &[Vec<usize>],
         sequence: &mut Vec<u8>,
         sequences: &mut Vec<Vec<u8>>,
     ) {
@@ -513,9 +1718,88 @@ mod sequence_gen {
             }
             return;
         }
-        for num in 1..=max {
-            if sequence.len() > 0 && sequence[sequence.len() - 1] == num {
-                continue; // Avoid adjacent identical numbers
+        for num in digits.clone() {
+            if conflicts[sequence.len()]
+                .iter()
+                .any(|j| sequence[*j] == num)
+            {
+                continue; // Avoid identical numbers in the same row or column
+            }
+            if let Some(a) = area {
+                match a.get(sequence.len()).unwrap() {
+                    //Check if number is possible within a cell
+                    Cell::Solution(n) => {
+                        if *n != num {
+                            continue;
+                        }
+                    }
+                    Cell::Possible(v) => {
+                        if !v.contains(&num) {
+                            continue;
+                        }
+                    }
+                }
+            }
+            sequence.push(num);
+            gen_seq_mul_recursive(
+                len,
+                digits.clone(),
+                target,
+                area,
+                conflicts,
+                sequence,
+                sequences,
+            );
+            sequence.pop();
+        }
+    }
+    /// Sequences of `len` numbers accepted by `accept`, for operations without their own enumeration.
+    /// `allowed(position, number)` rules out numbers early, so not every sequence has to be tried.
+    /// `conflicts[i]` lists earlier positions which can't hold the same number as position `i`.
+    pub fn generate_sequences_with<A: Fn(usize, u8) -> bool, F: Fn(&[u8]) -> bool>(
+        len: usize,
+        digits: RangeInclusive<u8>,
+        area: Option<&Vec<Cell>>,
+        conflicts: &[Vec<usize>],
+        allowed: &A,
+        accept: &F,
+    ) -> Vec<Vec<u8>> {
+        let mut sequences = Vec::new();
+        gen_seq_recursive(
+            digits,
+            area,
+            &conflicts[..len],
+            allowed,
+            accept,
+            &mut Vec::new(),
+            &mut sequences,
+        );
+        sequences
+    }
+    fn gen_seq_recursive<A: Fn(usize, u8) -> bool, F: Fn(&[u8]) -> bool>(
+        digits: RangeInclusive<u8>,
+        area: Option<&Vec<Cell>>,
+        conflicts: &[Vec<usize>],
+        allowed: &A,
+        accept: &F,
+        sequence: &mut Vec<u8>,
+        sequences: &mut Vec<Vec<u8>>,
+    ) {
+        if sequence.len() == conflicts.len() {
+            if accept(sequence) {
+                sequences.push(sequence.clone());
+            }
+            return;
+        }
+        for num in digits.clone() {
+            if !allowed(sequence.len(), num) {
+                continue;
+            }
+            if conflicts[sequence.len()]
+                .iter()
+                .any(|j| sequence[*j] == num)
+            {
+                continue;
             }
             if let Some(a) = area {
                 match a.get(sequence.len()).unwrap() {
@@ -533,37 +1817,60 @@ mod sequence_gen {
                 }
             }
             sequence.push(num);
-            gen_seq_mul_recursive(len, max, target, area, sequence, sequences);
+            gen_seq_recursive(
+                digits.clone(),
+                area,
+                conflicts,
+                allowed,
+                accept,
+                sequence,
+                sequences,
+            );
             sequence.pop();
         }
     }
+    /// `conflicts[i]` lists earlier positions which can't hold the same number as position `i`,
+    /// so it has `len` entries
     pub fn generate_sequences_sum(
         len: usize,
-        max: u8,
+        digits: RangeInclusive<u8>,
         target: u32,
         area: Option<&Vec<Cell>>,
+        conflicts: &[Vec<usize>],
     ) -> Vec<Vec<u8>> {
         let mut sequences = Vec::new();
-        gen_seq_sum_recursive(&mut sequences, &mut Vec::new(), len, max, target, area, 0);
+        gen_seq_sum_recursive(
+            &mut sequences,
+            &mut Vec::new(),
+            digits,
+            target,
+            area,
+            &conflicts[..len],
+            0,
+        );
         sequences
     }
     fn gen_seq_sum_recursive(
         sequences: &mut Vec<Vec<u8>>,
         sequence: &mut Vec<u8>,
-        len: usize,
-        max: u8,
+        digits: RangeInclusive<u8>,
         target: u32,
         area: Option<&Vec<Cell>>,
+        conflicts: &[Vec<usize>],
         sum: u32,
     ) {
+        let len = conflicts.len();
         if sequence.len() == len {
             if sum == target {
                 sequences.push(sequence.clone());
             }
             return;
         }
-        for num in 1..=max {
-            if sequence.len() > 0 && sequence[sequence.len() - 1] == num {
+        for num in digits.clone() {
+            if conflicts[sequence.len()]
+                .iter()
+                .any(|j| sequence[*j] == num)
+            {
                 continue;
             }
             if let Some(a) = area {
@@ -581,20 +1888,21 @@ mod sequence_gen {
                     }
                 }
             }
-            if sum + num as u32 + (len - sequence.len() - 1) as u32 * 1 > target {
+            let remaining = (len - sequence.len() - 1) as u32;
+            if sum + num as u32 + remaining * (*digits.start() as u32) > target {
                 continue;
             }
-            if sum + num as u32 + (len - sequence.len() - 1) as u32 * (max as u32) < target {
+            if sum + num as u32 + remaining * (*digits.end() as u32) < target {
                 continue;
             }
             sequence.push(num);
             gen_seq_sum_recursive(
                 sequences,
                 sequence,
-                len,
-                max,
+                digits.clone(),
                 target,
                 area,
+                conflicts,
                 sum + (num as u32),
             );
             sequence.pop();
@@ -609,24 +1917,40 @@ mod tests {
 
     #[test]
     fn generate_sequences_mul() {
-        let seq = sequence_gen::generate_sequences_mul(3, 5, 18, None); //Seq.0 [3, 2, 3]
+        let path = [vec![], vec![0], vec![1]]; //L-shaped cage, first and last cell don't share line
+        let seq = sequence_gen::generate_sequences_mul(3, 1..=5, 18, None, &path); //Seq.0 [3, 2, 3]
         assert_eq!(seq.len(), 1);
         assert_eq!(seq[0].len(), 3);
         assert_eq!(seq[0], vec![3, 2, 3]);
         let mut area = vec![Cell::Possible(vec![1,2,3,4,5]);3];
         area[0] = Cell::Solution(3);
-        let seq = sequence_gen::generate_sequences_mul(3, 5, 15, Some(&area));
+        let seq = sequence_gen::generate_sequences_mul(3, 1..=5, 15, Some(&area), &path);
         assert_eq!(seq.len(), 2);
         area[0] = Cell::Possible(vec![3,4,5]);
-        let seq = sequence_gen::generate_sequences_mul(3, 5, 15, Some(&area));
+        let seq = sequence_gen::generate_sequences_mul(3, 1..=5, 15, Some(&area), &path);
         assert_eq!(seq.len(), 4);
         area[0] = Cell::Possible(vec![2,4]);
-        let seq = sequence_gen::generate_sequences_mul(3, 5, 15, Some(&area));
+        let seq = sequence_gen::generate_sequences_mul(3, 1..=5, 15, Some(&area), &path);
         assert_eq!(seq.len(), 0);
     }
     #[test]
     fn  generate_sequences_div() {
-        assert_eq!(sequence_gen::generate_sequences_div(5, 2, None).len(), 4);
+        assert_eq!(sequence_gen::generate_sequences_div(1..=5, 2, None, true).len(), 4);
+        assert_eq!(sequence_gen::generate_sequences_div(1..=5, 1, None, false).len(), 5);
+    }
+    #[test]
+    fn cage_conflicts_test() {
+        let cage = Cage {target: 10, operation: MathOp::Add, cells: vec![0,5,1,4]}; //square, not ordered start-end
+        let board = KenkenPuzzle::new(4, vec![cage.clone()]);
+        let conflicts = board.cage_conflicts(&cage, &[]);
+        assert_eq!(conflicts, vec![vec![], vec![], vec![0,1], vec![0,1]]);
+        let seq = sequence_gen::generate_sequences_sum(4, 1..=4, 10, None, &conflicts);
+        assert!(seq.contains(&vec![1,1,4,4])); //diagonal cells can repeat
+        assert!(seq.iter().all(|s| s[0] != s[2] && s[0] != s[3] && s[1] != s[2] && s[1] != s[3]));
+        let row = Cage {target: 6, operation: MathOp::Mul, cells: vec![2,0,1]};
+        let conflicts = board.cage_conflicts(&row, &[]);
+        assert_eq!(sequence_gen::generate_sequences_mul(3, 1..=4, 6, None, &conflicts).len(), 6);
+        assert_eq!(sequence_gen::generate_sequences_mul(3, 1..=4, 4, None, &conflicts).len(), 0);
     }
     #[test]
     fn solve_test() {
@@ -712,12 +2036,478 @@ mod tests {
                 7,2,1,9,6,8,5,3,4,2,5,9,8,1,3,6,4,7,1,7,5,4,9,2,3,6,8,
                 5,6,4,7,3,9,1,8,2,4,8,2,3,5,6,7,9,1,8,3,6,1,2,7,4,5,9]);
     }
+    #[test]
+    fn max_solutions_test() {
+        let board = KenkenPuzzle::new(4, vec![]); //every latin square
+        assert_eq!(board.solve(&100, &0).unwrap().unwrap().len(), 576);
+        let limited = board.solve(&100, &5).unwrap().unwrap();
+        assert_eq!(limited.len(), 5);
+        //Limited search returns first solutions in search order, whichever branch finishes first
+        for (solution, first) in limited.iter().zip(board.solutions()) {
+            assert_eq!(solution.grid.0, first.grid.0);
+        }
+        assert_eq!(
+            board.solve(&2, &0).unwrap_err(),
+            SolverError::DepthExceeded { max_depth: 2 }
+        );
+        let options = SolverOptions {
+            max_nodes: Some(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            board.solve_with_options(&options).unwrap_err(),
+            SolverError::NodeLimitExceeded { max_nodes: 10 }
+        );
+        let options = SolverOptions {
+            time_limit: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert!(matches!(
+            board.solve_with_options(&options),
+            Err(SolverError::TimeLimitExceeded { .. })
+        ));
+        let token = CancellationToken::new();
+        let options = SolverOptions {
+            max_solutions: Some(5),
+            cancel: Some(token.clone()),
+            ..Default::default()
+        };
+        assert_eq!(board.solve_with_options(&options).unwrap().unwrap().len(), 5);
+        token.cancel();
+        assert_eq!(board.solve_with_options(&options).unwrap_err(), SolverError::Cancelled);
+    }
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_limits_test() {
+        //Branches race each other on many threads, but limits trip at the same node as in sequential search
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(8).build().unwrap();
+        let grids = |solutions: Vec<Solution>| solutions.into_iter().map(|s| s.grid.0).collect::<Vec<_>>();
+        for board in [KenkenPuzzle::new(4, vec![]), KenkenPuzzle::new(5, vec![])] {
+            for max_nodes in [20, 40, 80, 160] {
+                for max_solutions in [Some(1), Some(3), None] {
+                    let options = SolverOptions {max_nodes: Some(max_nodes), max_solutions, ..Default::default()};
+                    let mut sequential = board.solutions_with_options(&options);
+                    let found = grids(sequential.by_ref().collect());
+                    let expected = match sequential.error() {
+                        Some(error) => Err(error),
+                        None => Ok(found),
+                    };
+                    for _ in 0..10 {
+                        let parallel = pool.install(|| board.solve_with_options(&options));
+                        assert_eq!(parallel.map(|s| grids(s.unwrap_or_default())), expected);
+                    }
+                }
+            }
+        }
+    }
+    #[test]
+    fn deep_search_test() {
+        //Every cell is guessed, so search goes hundreds of guesses deep
+        let board = KenkenPuzzle::new(20, vec![]);
+        let solutions = board.solve(&usize::MAX, &1).unwrap().unwrap();
+        assert!(solutions[0].depth > 100);
+    }
+    #[test]
+    fn solutions_iterator_test() {
+        let board = KenkenPuzzle::new(4, vec![
+            Cage {target: 1, operation: MathOp::Free, cells: vec![0]},
+            Cage {target: 2, operation: MathOp::Free, cells: vec![5]}
+            ]);
+        let all = board.solve(&100, &0).unwrap().unwrap();
+        assert_eq!(board.solutions().count(), all.len());
+        let first_two: Vec<Solution> = board.solutions().take(2).collect();
+        assert_eq!(first_two[0].grid.0, all[0].grid.0);
+        assert_eq!(first_two[1].grid.0, all[1].grid.0);
+
+        let empty = KenkenPuzzle::new(4, vec![]);
+        let mut limited = empty.solutions_with_options(&SolverOptions {
+            max_nodes: Some(20),
+            ..Default::default()
+        });
+        assert!(limited.by_ref().count() > 0);
+        assert_eq!(limited.error(), Some(SolverError::NodeLimitExceeded { max_nodes: 20 }));
+    }
+    #[test]
+    fn ambiguity_test() {
+        let board = KenkenPuzzle::new(3, vec![ //last two rows can be swapped
+            Cage {target: 1, operation: MathOp::Free, cells: vec![0]},
+            Cage {target: 2, operation: MathOp::Free, cells: vec![1]},
+            Cage {target: 3, operation: MathOp::Free, cells: vec![2]},
+            Cage {target: 12, operation: MathOp::Add, cells: vec![3,4,5,8,7,6]}
+            ]);
+        let ambiguity = board.ambiguity(&SolverOptions::default()).unwrap().unwrap();
+        assert_ne!(ambiguity.first.grid.0, ambiguity.second.grid.0);
+        for cell in 0..9 {
+            let differs = ambiguity.first.grid.0[cell] != ambiguity.second.grid.0[cell];
+            assert_eq!(ambiguity.cells.contains(&cell), differs);
+        }
+        assert_eq!(ambiguity.cells, vec![3,4,5,6,7,8]);
+        assert_eq!(ambiguity.cages, vec![3]);
+        let board = KenkenPuzzle::new(3, vec![
+            Cage {target: 5, operation: MathOp::Add, cells: vec![0,1]},
+            Cage {target: 3, operation: MathOp::Add, cells: vec![2,5]},
+            Cage {target: 4, operation: MathOp::Add, cells: vec![3,6]},
+            Cage {target: 3, operation: MathOp::Add, cells: vec![4,7]},
+            Cage {target: 3, operation: MathOp::Free, cells: vec![8]}
+            ]);
+        assert!(board.ambiguity(&SolverOptions::default()).unwrap().is_none());
+    }
+    #[test]
+    fn diagnose_test() {
+        let options = SolverOptions::default();
+        let mut board = sample_board();
+        assert!(board.diagnose(&options).unwrap().is_none());
+        board.cages[3].target = 17; //at most 4+3+4+3 in two rows and two columns
+        assert_eq!(board.diagnose(&options).unwrap().unwrap(), vec![Constraint::Cage(3)]);
+        board.cages[3].target = 11; //target wrong, but fits the cage
+        let core = board.diagnose(&options).unwrap().unwrap();
+        assert!(core.len() > 1);
+        assert!(board.solve(&40, &1).unwrap().is_none());
+        let outside: Vec<Constraint> = board.constraints().into_iter().filter(|c| !core.contains(c)).collect();
+        assert!(!board.satisfiable(&outside, &options).unwrap());
+        for c in &core { //minimal
+            let ignored: Vec<Constraint> = outside.iter().copied().chain(std::iter::once(*c)).collect();
+            assert!(board.satisfiable(&ignored, &options).unwrap());
+        }
+        let board = KenkenPuzzle::new(3, vec![ //same digit twice in a row
+            Cage {target: 2, operation: MathOp::Free, cells: vec![0]},
+            Cage {target: 2, operation: MathOp::Free, cells: vec![2]}
+            ]);
+        assert_eq!(
+            board.diagnose(&options).unwrap().unwrap(),
+            vec![Constraint::Cage(0), Constraint::Cage(1), Constraint::Row(0)]
+        );
+    }
+    #[test]
+    fn repair_test() {
+        let options = SolverOptions::default();
+        let mut board = sample_board();
+        assert!(board.suggest_repairs(&options).unwrap().is_empty());
+        board.cages[0].target = 21; //mistyped 24
+        let repairs = board.suggest_repairs(&options).unwrap();
+        assert_eq!((repairs[0].cage, repairs[0].operation, repairs[0].target), (0, MathOp::Mul, 24));
+        assert_eq!(repairs[0].solution.grid.0, SAMPLE_SOLUTION.to_vec());
+        for repair in &repairs {
+            let mut fixed = board.clone();
+            fixed.cages[repair.cage].target = repair.target;
+            fixed.cages[repair.cage].operation = repair.operation;
+            let solutions = fixed.solve(&40, &2).unwrap().unwrap();
+            assert_eq!(solutions.len(), 1);
+            assert_eq!(solutions[0].grid.0, repair.solution.grid.0);
+        }
+        board.cages[0].target = 24;
+        let mut mystery = board.clone();
+        board.cages.truncate(4); //ambiguous
+        let repairs = board.suggest_repairs(&options).unwrap();
+        assert!(!repairs.is_empty());
+        for repair in &repairs {
+            let mut fixed = board.clone();
+            fixed.cages[repair.cage].target = repair.target;
+            fixed.cages[repair.cage].operation = repair.operation;
+            assert_eq!(fixed.solve(&40, &2).unwrap().unwrap().len(), 1);
+        }
+        for cage in mystery.cages.iter_mut() {
+            cage.operation = MathOp::Unknown;
+        }
+        assert_eq!(mystery.solve(&40, &2).unwrap().unwrap().len(), 1);
+        mystery.cages[0].target = 21;
+        let repairs = mystery.suggest_repairs(&options).unwrap();
+        assert!(!repairs.is_empty());
+        for repair in &repairs {
+            let mut fixed = mystery.clone();
+            fixed.cages[repair.cage].target = repair.target;
+            fixed.cages[repair.cage].operation = repair.operation;
+            assert_eq!(fixed.solve(&40, &2).unwrap().unwrap().len(), 1);
+        }
+        assert_eq!(typo_distance(21, 24), 1);
+        assert_eq!(typo_distance(7, 17), 1);
+        assert_eq!(typo_distance(12, 21), 2);
+    }
+    #[test]
+    fn givens_test() {
+        let mut board = sample_board();
+        board.cages.truncate(3);
+        assert!(board.solve(&40, &0).unwrap().unwrap().len() > 1);
+        board.givens = BTreeMap::from([(10, 3), (12, 1), (13, 4), (15, 3)]);
+        solve_test_board(&board, SAMPLE_SOLUTION.to_vec());
+        assert_eq!(board.solve(&40, &0).unwrap().unwrap().len(), 1);
+        assert!(board.validate().is_ok());
+
+        let text = board.format();
+        assert_eq!(text, "4<24.m.0,4,5>2.s.1,2>7.a.3,7,11>3.g.10>1.g.12>4.g.13>3.g.15>");
+        assert_eq!(KenkenPuzzle::parse(&text).unwrap().format(), text);
+        assert_eq!(KenkenPuzzle::parse("4<3.x.1>").unwrap_err(), PuzzleError::InvalidFormat);
+        assert_eq!(KenkenPuzzle::parse("4<3.g.1,2>").unwrap_err(), PuzzleError::InvalidFormat);
+        let rendered = board.render().unwrap();
+        assert_eq!(rendered.lines().count(), 13);
+        assert!(rendered.starts_with("+---+---+---+---+\n|24*|2-     |7+ |\n"));
+        assert!(rendered.contains("| 1 | 4 |   | 3 |"));
+
+        board.givens.insert(14, 1); //1 twice in last row
+        assert!(board.solve(&40, &1).unwrap().is_none());
+        assert_eq!(
+            board.diagnose(&SolverOptions::default()).unwrap().unwrap(),
+            vec![Constraint::Row(3), Constraint::Given(12), Constraint::Given(14)]
+        );
+        board.givens.insert(14, 5);
+        assert_eq!(board.validate(), Err(PuzzleError::InvalidGiven { cell: 14 }));
+        board.givens.remove(&14);
+        board.cages.push(Cage {target: 1, operation: MathOp::Sub, cells: vec![5,6]});
+        assert_eq!(board.validate(), Err(PuzzleError::OverlappingCages { cell: 5 }));
+        board.cages[3].cells = vec![6,16];
+        assert_eq!(board.validate(), Err(PuzzleError::CellOutOfGrid { cell: 16 }));
+        assert_eq!(board.render().unwrap_err(), PuzzleError::CellOutOfGrid { cell: 16 });
+        let out_of_grid = SolverError::InvalidPuzzle(PuzzleError::CellOutOfGrid { cell: 16 });
+        assert_eq!(board.solve(&40, &1).unwrap_err(), out_of_grid);
+        assert_eq!(board.diagnose(&SolverOptions::default()).unwrap_err(), out_of_grid);
+        board.cages[3].cells = vec![6,10,14];
+        assert_eq!(board.validate(), Err(PuzzleError::InvalidCageSize { cage: 3 }));
+    }
+    #[test]
+    fn generalized_sub_div_test() {
+        let mut board = sample_board();
+        board.cages[2] = Cage {target: 1, operation: MathOp::Sub, cells: vec![3,7,11]}; //4 - 2 - 1
+        assert_eq!(board.validate(), Err(PuzzleError::InvalidCageSize { cage: 2 }));
+        board.rules.generalized_sub_div = true;
+        assert!(board.validate().is_ok());
+        solve_test_board(&board, SAMPLE_SOLUTION.to_vec());
+        board.cages[2] = Cage {target: 8, operation: MathOp::Div, cells: vec![3,7,11]}; //not divisible
+        assert!(board.solve(&40, &1).unwrap().is_none());
+        board.cages[2].target = 2; //4 / 2 / 1
+        solve_test_board(&board, SAMPLE_SOLUTION.to_vec());
+        let text = board.format();
+        assert!(text.starts_with("4:generalized<"));
+        let parsed = KenkenPuzzle::parse(&text).unwrap();
+        assert_eq!(parsed.rules, board.rules);
+        assert_eq!(parsed.format(), text);
+        assert_eq!(MathOp::Sub.evaluate(&[2, 6, 1]), Some(3));
+        assert_eq!(MathOp::Div.evaluate(&[2, 8, 3]), None);
+    }
+    #[test]
+    fn unknown_operation_test() {
+        let mut board = sample_board();
+        for cage in board.cages.iter_mut() {
+            cage.operation = MathOp::Unknown;
+        }
+        let solutions = board.solve(&40, &0).unwrap().unwrap();
+        assert!(solutions.iter().any(|s| s.grid.0 == SAMPLE_SOLUTION));
+        let possible = |cell: &Cell| match cell {
+            Cell::Possible(v) => { let mut v = v.clone(); v.sort(); v }
+            Cell::Solution(n) => vec![*n],
+        };
+        let mut area = vec![Cell::Possible(vec![1,2,3,4]); 2];
+        assert!(board.cages[1].solve(&mut area, 4, &[vec![], vec![0]], &Rules::default()));
+        assert_eq!(possible(&area[0]), vec![1,2,3,4]); //2 can be 2*1, 2/1, 4-2, ...
+        board.cages[0].target = 5;
+        let mut area = vec![Cell::Possible(vec![1,2,3,4]); 3];
+        assert!(board.cages[0].solve(&mut area, 4, &[vec![], vec![0], vec![1]], &Rules::default()));
+        assert_eq!(possible(&area[0]), vec![1,2]); //only 1+3+1 or 2+1+2
+        //Min would give 2 from 2 and 3, but hidden operation is classic
+        let mystery = Cage {target: 2, operation: MathOp::Unknown, cells: vec![0,1]};
+        let mut area = vec![Cell::Solution(2), Cell::Solution(3)];
+        assert!(!mystery.solve(&mut area, 4, &[vec![], vec![0]], &Rules::default()));
+        let text = board.format();
+        assert!(text.contains("5.u.0,4,5>"));
+        assert_eq!(KenkenPuzzle::parse(&text).unwrap().format(), text);
+    }
+    #[test]
+    fn extra_operations_test() {
+        assert_eq!(MathOp::Mod.evaluate(&[3,7]), Some(1));
+        assert_eq!(MathOp::Pow.evaluate(&[3,2]), Some(9));
+        assert_eq!(MathOp::Lcm.evaluate(&[4,3,2]), Some(12));
+        assert_eq!(MathOp::Gcd.evaluate(&[6,4]), Some(2));
+        assert_eq!(MathOp::Concat.evaluate(&[2,1]), Some(21));
+        let board = KenkenPuzzle::new(4, vec![
+            Cage {target: 12, operation: MathOp::Lcm, cells: vec![0,4,5]},
+            Cage {target: 3, operation: MathOp::Pow, cells: vec![1,2]},
+            Cage {target: 4, operation: MathOp::Max, cells: vec![3,7,11]},
+            Cage {target: 2, operation: MathOp::Min, cells: vec![6,10,14,15]},
+            Cage {target: 21, operation: MathOp::Concat, cells: vec![8,12]},
+            Cage {target: 0, operation: MathOp::Mod, cells: vec![9,13]}
+            ]);
+        let solutions = board.solve(&40, &0).unwrap().unwrap();
+        assert!(solutions.iter().any(|s| s.grid.0 == SAMPLE_SOLUTION));
+        let mut area = vec![Cell::Possible(vec![1,2,3,4]); 2];
+        assert!(board.cages[4].solve(&mut area, 4, &[vec![], vec![0]], &Rules::default()));
+        assert_eq!(area[0], Cell::Possible(vec![2]));
+        assert_eq!(area[1], Cell::Possible(vec![1]));
+        let text = board.format();
+        assert!(text.contains("21.cat.8,12>") && text.contains("12.lcm.0,4,5>"));
+        assert_eq!(KenkenPuzzle::parse(&text).unwrap().format(), text);
+        assert!(board.render().unwrap().contains("21||"));
+    }
+    #[test]
+    fn zero_based_test() {
+        assert_eq!(MathOp::Div.evaluate(&[0,3]), Some(0));
+        assert_eq!(MathOp::Div.evaluate(&[0,0]), None);
+        let mut board = KenkenPuzzle::new(4, vec![
+            Cage {target: 6, operation: MathOp::Mul, cells: vec![0,4,5]},
+            Cage {target: 0, operation: MathOp::Div, cells: vec![1,2]},
+            Cage {target: 4, operation: MathOp::Add, cells: vec![3,7,11]},
+            Cage {target: 8, operation: MathOp::Add, cells: vec![6,10,14,15]},
+            Cage {target: 1, operation: MathOp::Sub, cells: vec![8,12]},
+            Cage {target: 0, operation: MathOp::Mul, cells: vec![9,13]}
+            ]);
+        board.rules.min_digit = 0;
+        let expected = vec![3,2,0,1,2,1,3,0,1,0,2,3,0,3,1,2];
+        let solutions = board.solve(&40, &0).unwrap().unwrap();
+        assert!(solutions.iter().any(|s| s.grid.0 == expected));
+        assert!(solutions.iter().all(|s| s.grid.0.iter().all(|d| *d < 4)));
+        let mut area = vec![Cell::Possible(vec![0,1,2,3]); 2];
+        assert!(board.cages[1].solve(&mut area, 4, &[vec![], vec![0]], &board.rules));
+        assert_eq!(area[0], Cell::Possible(vec![0,1,2,3])); //0 / 1, 0 / 2, 0 / 3 both ways
+        board.givens.insert(2, 0);
+        assert_eq!(board.validate(), Ok(()));
+        let text = board.format();
+        assert!(text.starts_with("4:min=0<"));
+        assert_eq!(KenkenPuzzle::parse(&text).unwrap().rules, board.rules);
+        board.rules.min_digit = 1;
+        assert_eq!(board.validate(), Err(PuzzleError::InvalidGiven {cell: 2}));
+        board.rules.min_digit = 252;
+        board.givens.clear();
+        assert_eq!(board.validate(), Ok(())); //digits 252..=255
+        board.rules.min_digit = 253;
+        assert_eq!(board.validate(), Err(PuzzleError::DigitsOutOfRange));
+        let invalid = SolverError::InvalidPuzzle(PuzzleError::DigitsOutOfRange);
+        assert_eq!(board.solve(&40, &0).unwrap_err(), invalid); //not an empty search over saturated digits
+        let mut solutions = board.solutions();
+        assert!(solutions.next().is_none());
+        assert_eq!(solutions.error(), Some(invalid));
+    }
+    #[test]
+    fn boxes_test() {
+        let mut rules = Rules {boxes: true, ..Rules::default()};
+        assert_eq!(rules.box_shape(4), Some((2, 2)));
+        assert_eq!(rules.box_shape(6), Some((2, 3)));
+        assert_eq!(rules.box_shape(8), Some((2, 4)));
+        assert_eq!(rules.box_shape(9), Some((3, 3)));
+        assert_eq!(rules.box_shape(7), None);
+        let mut board = KenkenPuzzle::new(4, vec![
+            Cage {target: 10, operation: MathOp::Add, cells: vec![0,1,4,5]},
+            Cage {target: 10, operation: MathOp::Add, cells: vec![2,3,6,7]},
+            Cage {target: 10, operation: MathOp::Add, cells: vec![8,9,12,13]},
+            Cage {target: 10, operation: MathOp::Add, cells: vec![10,11,14,15]}
+            ]);
+        board.rules = rules;
+        let units = board.units();
+        assert_eq!(units[9], (Constraint::Box(1), vec![2,3,6,7]));
+        let solutions = board.solve(&40, &0).unwrap().unwrap();
+        assert_eq!(solutions.len(), 288); //every 4x4 sudoku
+        let text = board.format();
+        assert!(text.starts_with("4:boxes<"));
+        assert_eq!(KenkenPuzzle::parse(&text).unwrap().rules, rules);
+        board.cages[0].target = 9;
+        let core = board.diagnose(&SolverOptions::default()).unwrap().unwrap();
+        assert_eq!(core, vec![Constraint::Cage(0), Constraint::Box(0)]); //1+2+2+4 fits without boxes
+        let others: Vec<Constraint> = board.constraints().into_iter().filter(|c| !core.contains(c)).collect();
+        assert!(!board.satisfiable(&others, &SolverOptions::default()).unwrap()); //core is unsatisfiable alone
+        let mut board = KenkenPuzzle::new(5, vec![]);
+        rules.boxes = true;
+        board.rules = rules;
+        assert_eq!(board.validate(), Err(PuzzleError::UnsupportedRules));
+    }
+    #[test]
+    fn diagonals_test() {
+        let mut board = KenkenPuzzle::new(4, vec![]);
+        assert_eq!(board.solve(&40, &0).unwrap().unwrap().len(), 576);
+        board.rules.diagonals = true;
+        assert_eq!(board.solve(&40, &0).unwrap().unwrap().len(), 48); //diagonal Latin squares
+        assert!(board.peers(5, 15) && board.peers(3, 9) && !board.peers(1, 6));
+        let text = board.format();
+        assert!(text.starts_with("4:diagonals<"));
+        assert_eq!(KenkenPuzzle::parse(&text).unwrap().rules, board.rules);
+        board.givens.insert(0, 1);
+        board.givens.insert(5, 1);
+        let core = board.diagnose(&SolverOptions::default()).unwrap().unwrap();
+        assert_eq!(core, vec![Constraint::Diagonal(0), Constraint::Given(0), Constraint::Given(5)]);
+        board.size = 3;
+        assert_eq!(board.validate(), Err(PuzzleError::UnsupportedRules));
+    }
+    #[test]
+    fn inequalities_test() {
+        let mut board = KenkenPuzzle::new(3, vec![]);
+        board.inequalities = vec![
+            Inequality {greater: 0, less: 1},
+            Inequality {greater: 1, less: 2},
+            Inequality {greater: 3, less: 6},
+        ];
+        let solutions = board.solve(&40, &0).unwrap().unwrap();
+        assert_eq!(solutions.len(), 1);
+        assert_eq!(solutions[0].grid.0, vec![3,2,1,2,1,3,1,3,2]);
+        let text = board.format();
+        assert!(text.ends_with("<0.gt.1>1.gt.2>3.gt.6>"));
+        assert_eq!(KenkenPuzzle::parse(&text).unwrap().inequalities, board.inequalities);
+        let rendered = board.render().unwrap();
+        assert!(rendered.contains("|   >   >   |"));
+        assert!(rendered.contains("+-v-+---+---+"));
+        board.inequalities.push(Inequality {greater: 1, less: 0});
+        let core = board.diagnose(&SolverOptions::default()).unwrap().unwrap();
+        assert_eq!(core, vec![Constraint::Inequality(0), Constraint::Inequality(3)]);
+        board.inequalities.push(Inequality {greater: 2, less: 3});
+        assert_eq!(board.validate(), Err(PuzzleError::InvalidInequality {inequality: 4}));
+    }
+    #[test]
+    fn distinct_cages_test() {
+        let cage = Cage {target: 10, operation: MathOp::Add, cells: vec![0,1,4,5]};
+        let mut board = KenkenPuzzle::new(4, vec![cage.clone()]);
+        board.rules.distinct_cages = true;
+        let conflicts = board.cage_conflicts(&cage, &[]);
+        assert_eq!(conflicts, vec![vec![], vec![0], vec![0,1], vec![0,1,2]]);
+        let seq = sequence_gen::generate_sequences_sum(4, 1..=4, 10, None, &conflicts);
+        assert_eq!(seq.len(), 24); //every order of 1+2+3+4, 1+1+4+4 no longer fits
+        let diagonal = Cage {target: 1, operation: MathOp::Sub, cells: vec![0,5]};
+        assert_eq!(board.cage_conflicts(&diagonal, &[]), vec![vec![], vec![0]]);
+        board.cages = vec![Cage {target: 1, operation: MathOp::Div, cells: vec![0,5]}];
+        assert!(board.solve(&40, &1).unwrap().is_none()); //1 / 1 needs the same digit twice
+        let text = board.format();
+        assert!(text.starts_with("4:distinct<"));
+        assert_eq!(KenkenPuzzle::parse(&text).unwrap().rules, board.rules);
+    }
+    #[test]
+    fn regions_test() {
+        let mut board = KenkenPuzzle::new(4, vec![]);
+        let regions = vec![vec![0,1,2,5], vec![3,6,7,11], vec![4,8,9,12], vec![10,13,14,15]];
+        let fits = |grid: &[u8]| regions.iter().all(|region| {
+            let mut digits: Vec<u8> = region.iter().map(|cell| grid[*cell]).collect();
+            digits.sort();
+            digits == vec![1,2,3,4]
+        });
+        let latin = board.solve(&40, &0).unwrap().unwrap();
+        let expected = latin.iter().filter(|s| fits(&s.grid.0)).count();
+        board.regions = regions.clone();
+        let solutions = board.solve(&40, &0).unwrap().unwrap();
+        assert!(expected > 0);
+        assert_eq!(solutions.len(), expected);
+        assert!(solutions.iter().all(|s| fits(&s.grid.0)));
+        assert_eq!(board.validate(), Ok(()));
+        let text = board.format();
+        assert!(text.ends_with("<0.r.0,1,2,5>1.r.3,6,7,11>2.r.4,8,9,12>3.r.10,13,14,15>"));
+        assert_eq!(KenkenPuzzle::parse(&text).unwrap().regions, regions);
+        board.givens.insert(0, 1);
+        board.givens.insert(5, 1);
+        let core = board.diagnose(&SolverOptions::default()).unwrap().unwrap();
+        assert_eq!(core, vec![Constraint::Region(0), Constraint::Given(0), Constraint::Given(5)]);
+        board.regions[3].pop();
+        assert_eq!(board.validate(), Err(PuzzleError::InvalidRegions));
+    }
+    /// 4x4 board with unique solution `SAMPLE_SOLUTION`, tests change its clues
+    fn sample_board() -> KenkenPuzzle {
+        KenkenPuzzle::new(4, vec![
+            Cage {target: 24, operation: MathOp::Mul, cells: vec![0,4,5]},
+            Cage {target: 2, operation: MathOp::Sub, cells: vec![1,2]},
+            Cage {target: 7, operation: MathOp::Add, cells: vec![3,7,11]},
+            Cage {target: 12, operation: MathOp::Add, cells: vec![6,10,14,15]},
+            Cage {target: 2, operation: MathOp::Div, cells: vec![8,12]},
+            Cage {target: 3, operation: MathOp::Sub, cells: vec![9,13]}
+            ])
+    }
+    const SAMPLE_SOLUTION: [u8; 16] = [4,3,1,2,3,2,4,1,2,1,3,4,1,4,2,3];
     fn solve_test_board(board: &KenkenPuzzle, expected: Vec<u8>) {
         if let Some(x) = board.solve(&40, &1).unwrap() {
             assert_eq!(x[0].grid.0, expected);
         }
         else {
-            assert!(false);
+            panic!("No solution found");
         }
     }
 }